@@ -0,0 +1,626 @@
+use crate::connection::{self, BodyLength, Limits, ReadError, CONTINUE};
+use crate::http::response::Body;
+use crate::http::{ChunkedDecoder, Headers, Method, ParseError, Request, Response, StatusCode};
+use crate::server::{Handler, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::shutdown::ShutdownHandle;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{self, ErrorKind, Read};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+
+const READ_CHUNK_SIZE: usize = 4096;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// The async counterpart of `Handler`. The returned future may borrow both
+// the handler and the request, so it can keep using them across awaits.
+// Handlers that are not async yet can run here wrapped in `Blocking`.
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn handle_request<'a>(&'a self, request: &'a mut Request<'_>) -> BoxFuture<'a, Response>;
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        warn!("Failed to parse request: {}", e);
+        Response::new(e.status_code(), None)
+    }
+
+    fn handle_panic(&self) -> Response {
+        Response::new(StatusCode::InternalServerError, None)
+    }
+}
+
+// Closures work as handlers too, if they take what they need from the
+// request before returning their future:
+//
+//   |request: &mut Request| {
+//       let path = request.path().to_string();
+//       async move { Response::builder().body(path) }
+//   }
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(&mut Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn handle_request<'a>(&'a self, request: &'a mut Request<'_>) -> BoxFuture<'a, Response> {
+        Box::pin(self(request))
+    }
+}
+
+// Runs a blocking `Handler` on the async server, so handlers can be moved
+// over one at a time. Each request holds on to a runtime worker thread
+// while it runs, so this needs the multi-threaded runtime `run` starts.
+pub struct Blocking<H> {
+    handler: H,
+}
+
+impl<H: Handler> Blocking<H> {
+    pub fn new(handler: H) -> Self {
+        Self { handler }
+    }
+}
+
+impl<H: Handler> AsyncHandler for Blocking<H> {
+    fn handle_request<'a>(&'a self, request: &'a mut Request<'_>) -> BoxFuture<'a, Response> {
+        Box::pin(async move { task::block_in_place(|| self.handler.handle_request(request)) })
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.handler.handle_bad_request(e)
+    }
+
+    fn handle_panic(&self) -> Response {
+        self.handler.handle_panic()
+    }
+}
+
+// Serves HTTP/1.1 on tokio, so idle keep-alive connections cost a task each
+// instead of a thread. Configured the same way as `Server`, which remains
+// the one to use for HTTPS.
+pub struct AsyncServer {
+    addrs: Vec<String>,
+    workers: Option<usize>,
+    limits: Limits,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl AsyncServer {
+    pub fn new(addr: String) -> Self {
+        Self {
+            addrs: vec![addr],
+            workers: None,
+            limits: Limits::default(),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Also listen on `addr`, serving it the same way as the first address.
+    pub fn bind(mut self, addr: String) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Set the number of runtime worker threads `run` starts. By default
+    /// there is one per CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "a server needs at least one worker");
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Set the largest request body, in bytes, the server will accept.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
+
+    /// Set how long an idle keep-alive connection is held open.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Set how many requests one connection may make before it is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_requests` is zero.
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        assert!(max_requests > 0, "a connection must be allowed one request");
+        self.limits.max_requests = max_requests;
+        self
+    }
+
+    /// Set how long shutting down waits for the requests in flight.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// A handle that stops the server from another thread or task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Start a runtime and serve connections on it until shut down.
+    /// Fails if an address cannot be bound.
+    pub fn run(self, handler: impl AsyncHandler) -> io::Result<()> {
+        let mut builder = runtime::Builder::new_multi_thread();
+        if let Some(workers) = self.workers {
+            builder.worker_threads(workers);
+        }
+        builder.enable_all().build()?.block_on(self.serve(handler))
+    }
+
+    /// Serve connections on the current runtime until shut down.
+    pub async fn serve(self, handler: impl AsyncHandler) -> io::Result<()> {
+        let handler: Arc<dyn AsyncHandler> = Arc::new(handler);
+
+        let mut listeners = Vec::new();
+        for addr in &self.addrs {
+            let listener = TcpListener::bind(addr).await.map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot listen on {}: {}", addr, e))
+            })?;
+            listeners.push(listener);
+            info!("Listening on http://{} (async)", addr);
+        }
+
+        // Each listener accepts on its own task and hands connections over
+        // here, where they are spawned into a set that can be drained.
+        let (accepted, mut incoming) = mpsc::channel(self.addrs.len());
+        let acceptors: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(accept(listener, accepted.clone())))
+            .collect();
+        drop(accepted);
+
+        let (closing, closing_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        let mut poll = time::interval(SHUTDOWN_POLL_INTERVAL);
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                Some((stream, peer_addr)) = incoming.recv() => {
+                    connections.spawn(serve_connection(
+                        stream,
+                        peer_addr,
+                        Arc::clone(&handler),
+                        self.limits,
+                        closing_rx.clone(),
+                    ));
+                }
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = poll.tick() => {}
+            }
+        }
+
+        info!("Shutting down, {} connections open", connections.len());
+        for acceptor in acceptors {
+            acceptor.abort();
+        }
+        let _ = closing.send(true);
+        let drained = time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Closing {} connections still busy after {:?}",
+                connections.len(),
+                self.shutdown_timeout
+            );
+            connections.shutdown().await;
+        }
+        Ok(())
+    }
+}
+
+async fn accept(listener: TcpListener, accepted: mpsc::Sender<(TcpStream, SocketAddr)>) {
+    loop {
+        match listener.accept().await {
+            Ok(connection) => {
+                if accepted.send(connection).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => error!("Failed to establish a connection: {}", e),
+        }
+    }
+}
+
+// Serves requests until the client is done with the connection. Once
+// `closing` turns true, a connection waiting for its next request closes
+// and a busy one closes after its response.
+async fn serve_connection(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    handler: Arc<dyn AsyncHandler>,
+    limits: Limits,
+    mut closing: watch::Receiver<bool>,
+) {
+    let mut connection = AsyncConnection::new(stream, limits.keep_alive_timeout);
+    let handler = &*handler;
+
+    for served in 1.. {
+        let head = tokio::select! {
+            head = connection.read_head() => head,
+            _ = closing.wait_for(|closing| *closing) => return,
+        };
+        let reusable = served < limits.max_requests;
+        let request = (head, peer_addr);
+        if !serve_request(
+            &mut connection,
+            request,
+            handler,
+            &limits,
+            &closing,
+            reusable,
+        )
+        .await
+        {
+            return;
+        }
+    }
+}
+
+// The async twin of `server::serve_request`: answer the request whose head
+// has been read, and report whether the connection can carry another.
+async fn serve_request<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut AsyncConnection<S>,
+    (head, peer_addr): (Result<Vec<u8>, ReadError>, SocketAddr),
+    handler: &dyn AsyncHandler,
+    limits: &Limits,
+    closing: &watch::Receiver<bool>,
+    reusable: bool,
+) -> bool {
+    let head = match head {
+        Ok(head) => head,
+        Err(e) => {
+            if let Some(response) = read_error_response(e, handler) {
+                send_response(connection, response, false, false).await;
+            }
+            return false;
+        }
+    };
+    debug!("Received a request: {}", String::from_utf8_lossy(&head));
+
+    let mut request = match Request::try_from(&head[..]) {
+        Ok(request) => request,
+        Err(e) => {
+            send_response(connection, handler.handle_bad_request(&e), false, false).await;
+            return false;
+        }
+    };
+    request.set_remote_addr(peer_addr);
+    let keep_alive = reusable && !request.headers().contains_token("Connection", "close");
+    let head_only = *request.method() == Method::HEAD;
+
+    match connection.read_body(request.headers(), limits).await {
+        Ok(body) => request.set_body(body),
+        Err(e) => {
+            if let Some(response) = read_error_response(e, handler) {
+                send_response(connection, response, false, head_only).await;
+            }
+            return false;
+        }
+    }
+
+    // As on the blocking server, a panic takes down this request only.
+    let response = CatchUnwind::new(|| handler.handle_request(&mut request)).await;
+    // A server shutting down closes connections after the response.
+    let keep_alive = keep_alive && !*closing.borrow();
+    match response {
+        Ok(mut response) => {
+            // Upgraded connections need a blocking socket to hand over.
+            if response.take_upgrade().is_some() {
+                error!("The async server cannot upgrade {}", request.target());
+                response = Response::new(StatusCode::NotImplemented, None);
+            }
+            send_response(connection, response, keep_alive, head_only).await
+        }
+        Err(_) => {
+            error!(
+                "Handler panicked serving {} {}",
+                request.method().as_str(),
+                request.target()
+            );
+            send_response(connection, handler.handle_panic(), false, head_only).await
+        }
+    }
+}
+
+// Returns whether the connection is still open for another request.
+async fn send_response<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut AsyncConnection<S>,
+    mut response: Response,
+    keep_alive: bool,
+    head_only: bool,
+) -> bool {
+    let keep_alive = keep_alive && !response.headers().contains_token("Connection", "close");
+    if !keep_alive {
+        response.headers_mut().insert("Connection", "close");
+    }
+
+    // The head is serialized the same way the blocking server sends it.
+    let mut head = Vec::new();
+    let mut sent = response.send_head(&mut head);
+    if sent.is_ok() {
+        let include_body = !head_only && response.allows_body();
+        sent = write_response(
+            &mut connection.stream,
+            &head,
+            response.take_body(),
+            include_body,
+        )
+        .await;
+    }
+
+    match sent {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            warn!("Failed to send response: {}", e);
+            false
+        }
+    }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    head: &[u8],
+    body: Body,
+    include_body: bool,
+) -> io::Result<()> {
+    stream.write_all(head).await?;
+    if include_body {
+        match body {
+            Body::Bytes(bytes) => stream.write_all(&bytes).await?,
+            Body::Stream(reader, len) => write_stream(stream, reader, len).await?,
+            Body::Chunked(reader) => write_chunked(stream, reader).await?,
+            Body::Omitted(len) if len > 0 => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "omitted body can only answer HEAD",
+                ))
+            }
+            Body::Omitted(_) => {}
+        }
+    }
+    stream.flush().await
+}
+
+// Streamed bodies come from blocking readers, such as files, so each chunk
+// is read on the blocking thread pool.
+async fn write_stream<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut reader: Box<dyn Read + Send>,
+    len: u64,
+) -> io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let size = remaining.min(STREAM_CHUNK_SIZE as u64) as usize;
+        let (returned, chunk) = read_chunk(reader, size).await?;
+        reader = returned;
+
+        // The length was already promised in Content-Length.
+        if chunk.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "streamed body ended early",
+            ));
+        }
+        stream.write_all(&chunk).await?;
+        remaining -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+// The same, for a body of unknown length sent with chunked coding.
+async fn write_chunked<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut reader: Box<dyn Read + Send>,
+) -> io::Result<()> {
+    loop {
+        let (returned, chunk) = read_chunk(reader, STREAM_CHUNK_SIZE).await?;
+        reader = returned;
+        if chunk.is_empty() {
+            return stream.write_all(b"0\r\n\r\n").await;
+        }
+        stream
+            .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+            .await?;
+        stream.write_all(&chunk).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+}
+
+// Read up to `size` bytes on the blocking thread pool, handing the reader
+// back for the next read.
+async fn read_chunk(
+    mut reader: Box<dyn Read + Send>,
+    size: usize,
+) -> io::Result<(Box<dyn Read + Send>, Vec<u8>)> {
+    let (reader, chunk) = task::spawn_blocking(move || {
+        let mut chunk = vec![0; size];
+        let read = reader.read(&mut chunk).map(|n| {
+            chunk.truncate(n);
+            chunk
+        });
+        (reader, read)
+    })
+    .await
+    .map_err(io::Error::other)?;
+    Ok((reader, chunk?))
+}
+
+fn read_error_response(e: ReadError, handler: &dyn AsyncHandler) -> Option<Response> {
+    match e {
+        ReadError::Closed | ReadError::TimedOut => None,
+        ReadError::Io(e) => {
+            warn!("Failed to read from connection: {}", e);
+            None
+        }
+        ReadError::Parse(e) => Some(handler.handle_bad_request(&e)),
+    }
+}
+
+// `Connection` for async streams. The framing itself is shared with it;
+// only the waiting for more bytes differs. Every read is bounded by the
+// keep-alive timeout, as the blocking server's read timeout is.
+struct AsyncConnection<S> {
+    stream: S,
+    buf: Vec<u8>,
+    timeout: Duration,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncConnection<S> {
+    fn new(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            timeout,
+        }
+    }
+
+    async fn read_head(&mut self) -> Result<Vec<u8>, ReadError> {
+        loop {
+            if let Some(head) = connection::split_head(&mut self.buf)? {
+                return Ok(head);
+            }
+            if self.fill().await? == 0 {
+                return Err(connection::closed_early(&self.buf));
+            }
+        }
+    }
+
+    async fn read_body(
+        &mut self,
+        headers: &Headers<'_>,
+        limits: &Limits,
+    ) -> Result<Vec<u8>, ReadError> {
+        let length = connection::body_length(headers, limits)?;
+        if length != BodyLength::Empty && connection::expects_continue(headers) {
+            self.stream.write_all(CONTINUE.as_bytes()).await?;
+        }
+
+        let mut chunked = ChunkedDecoder::new();
+        loop {
+            if let Some(body) = connection::split_body(&mut self.buf, length, &mut chunked, limits)?
+            {
+                return Ok(body);
+            }
+            if self.fill().await? == 0 {
+                return Err(length.cut_short().into());
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> Result<usize, ReadError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let n = time::timeout(self.timeout, self.stream.read(&mut chunk))
+            .await
+            .map_err(|_| ReadError::TimedOut)??;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+// catch_unwind for a handler's future: a panic while creating or polling it
+// becomes an Err instead of taking down the connection's task.
+struct CatchUnwind<'a> {
+    future: Option<BoxFuture<'a, Response>>,
+    panicked: Option<Box<dyn std::any::Any + Send>>,
+}
+
+impl<'a> CatchUnwind<'a> {
+    fn new(create: impl FnOnce() -> BoxFuture<'a, Response>) -> Self {
+        match panic::catch_unwind(AssertUnwindSafe(create)) {
+            Ok(future) => Self {
+                future: Some(future),
+                panicked: None,
+            },
+            Err(panicked) => Self {
+                future: None,
+                panicked: Some(panicked),
+            },
+        }
+    }
+}
+
+impl Future for CatchUnwind<'_> {
+    type Output = thread::Result<Response>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let future = match &mut self.future {
+            Some(future) => future,
+            None => {
+                return Poll::Ready(Err(self.panicked.take().expect("polled after completion")))
+            }
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(response)) => Poll::Ready(Ok(response)),
+            Err(panicked) => {
+                self.future = None;
+                Poll::Ready(Err(panicked))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+
+    #[test]
+    fn passes_conformance_suite() {
+        conformance::check(|addr| {
+            let server = AsyncServer::new(addr)
+                .workers(2)
+                .max_body_size(conformance::MAX_BODY_SIZE);
+            let shutdown = server.shutdown_handle();
+            let running = thread::spawn(move || server.run(Blocking::new(conformance::respond)));
+            (shutdown, running)
+        });
+    }
+
+    #[test]
+    fn runs_async_handlers() {
+        let addr = conformance::free_addr();
+        let server = AsyncServer::new(addr.clone()).workers(1);
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || {
+            server.run(|request: &mut Request| {
+                let path = request.path().to_string();
+                async move {
+                    time::sleep(Duration::from_millis(10)).await;
+                    Response::builder().body(format!("async {}", path))
+                }
+            })
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let response = conformance::get(&addr, "/page");
+        assert!(response.ends_with("\r\n\r\nasync /page"));
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}
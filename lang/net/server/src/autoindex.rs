@@ -0,0 +1,133 @@
+use super::escape;
+use super::http::date::format_http_date;
+use super::http::percent::percent_encode_path;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+// The contents of a directory, for browsing when it has no index.html.
+// Subdirectories come first, then files, each sorted by name. Hidden
+// entries are left out.
+pub struct Listing {
+    url_path: String,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Listing {
+    /// List `dir`, which is served under `url_path`.
+    pub fn read(dir: &Path, url_path: &str) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            // Follows symlinks, so a dangling one is skipped.
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            entries.push(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(Self {
+            url_path: url_path.to_string(),
+            entries,
+        })
+    }
+
+    pub fn to_html(&self) -> String {
+        let title = escape::html(&self.url_path);
+        let mut html = format!(
+            concat!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+                "<title>Index of {0}</title>\n</head>\n<body>\n",
+                "<h1>Index of {0}</h1>\n<table>\n",
+                "<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+            ),
+            title
+        );
+        if self.url_path != "/" {
+            html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+        }
+
+        for entry in &self.entries {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            let size = if entry.is_dir {
+                "-".to_string()
+            } else {
+                entry.len.to_string()
+            };
+            let _ = writeln!(
+                html,
+                "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+                escape::html(&percent_encode_path(&entry.name)),
+                suffix,
+                escape::html(&entry.name),
+                suffix,
+                size,
+                entry.modified.map(format_http_date).unwrap_or_default()
+            );
+        }
+
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+
+    // [{"name":"app.js","type":"file","size":14,"modified":"Sun, 06 Nov 1994 08:49:37 GMT"}]
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let modified = match entry.modified {
+                    Some(modified) => format!("\"{}\"", format_http_date(modified)),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                    escape::json(&entry.name),
+                    if entry.is_dir { "directory" } else { "file" },
+                    entry.len,
+                    modified
+                )
+            })
+            .collect();
+
+        format!("[{}]", entries.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_directories_first() {
+        let dir = std::env::temp_dir().join(format!("autoindex_{}", std::process::id()));
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+
+        let listing = Listing::read(&dir, "/files/").unwrap();
+        let names: Vec<&str> = listing.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["b", "a.txt"]);
+        assert!(listing.to_html().contains("<h1>Index of /files/</h1>"));
+    }
+}
@@ -0,0 +1,534 @@
+use super::http::request::parse_header;
+use super::http::{ParseError, Request, Response, StatusCode};
+use super::path_resolver::{has_path_prefix, normalize_path, PathResolver, ResolveError};
+use super::server::Handler;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CGI_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_OUTPUT_SIZE: usize = 16 * 1024 * 1024;
+// How often a script that has closed stdout is checked for having exited.
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+const SERVER_SOFTWARE: &str = concat!("server/", env!("CARGO_PKG_VERSION"));
+
+// A URL path prefix and the directory its scripts are in.
+type Route = (String, PathResolver);
+type Script<'a, 'b> = (&'a Route, &'b str, &'b str);
+
+// Runs CGI/1.1 scripts (RFC 3875) for requests under a path prefix, and
+// hands everything else to the wrapped handler, which should not serve the
+// scripts' source itself:
+//
+//   CgiHandler::new(WebsiteHandler::new(public_path).exclude("public/cgi-bin"))
+//       .route("/cgi-bin/", "public/cgi-bin")
+//
+// The first path segment after a prefix names an executable in its
+// directory and the rest is passed to it as PATH_INFO, so both
+// `/cgi-bin/guestbook/entries` and `//cgi-bin/./guestbook/entries` run
+// `guestbook`. Scripts get the request body on stdin and a clean
+// environment holding only the CGI variables and PATH. Whatever they write
+// to stderr is logged.
+//
+// A script that outlives the timeout is killed and answered with 504
+// Gateway Timeout. One that fails, writes more than the output limit, or
+// writes something other than a CGI response gives 502 Bad Gateway.
+pub struct CgiHandler<H> {
+    handler: H,
+    routes: Vec<Route>,
+    timeout: Duration,
+    max_output_size: usize,
+}
+
+impl<H: Handler> CgiHandler<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            routes: Vec::new(),
+            timeout: DEFAULT_CGI_TIMEOUT,
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+        }
+    }
+
+    /// Run the executables in `dir` for paths under `prefix`. Routes are
+    /// tried in the order they were added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dir` does not exist.
+    pub fn route(mut self, prefix: &str, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let resolver = PathResolver::new(dir)
+            .unwrap_or_else(|e| panic!("cannot run scripts in {}: {}", dir.display(), e));
+        self.routes.push((prefix.to_string(), resolver));
+        self
+    }
+
+    /// Set how long a script may run before it is killed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the most a script may write to stdout, headers included.
+    pub fn max_output_size(mut self, max_output_size: usize) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    // The script a normalized path names: its route, its name and the
+    // PATH_INFO that follows it.
+    fn script_for<'a>(&self, path: &'a str) -> Option<Script<'_, 'a>> {
+        self.routes.iter().find_map(|route| {
+            if !has_path_prefix(path, &route.0) {
+                return None;
+            }
+            let rest = &path[route.0.trim_end_matches('/').len()..];
+            let rest = rest.strip_prefix('/').unwrap_or(rest);
+            Some(match rest.find('/') {
+                Some(end) => (route, &rest[..end], &rest[end..]),
+                None => (route, rest, ""),
+            })
+        })
+    }
+
+    fn run(&self, request: &Request, script: Script) -> Result<Response, CgiError> {
+        let (route, name, path_info) = script;
+        let resolver = &route.1;
+        let script = resolver.resolve(&format!("/{}", name))?;
+        if !is_executable(&script) {
+            return Err(CgiError::NotFound);
+        }
+
+        let mut child = Command::new(&script)
+            .current_dir(resolver.root())
+            .env_clear()
+            .envs(environment(request, route, &script, name, path_info))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Feed stdin and drain stdout and stderr on their own threads, so a
+        // script that writes before it has read all its input cannot block.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let body = request.body().to_vec();
+        thread::spawn(move || stdin.write_all(&body));
+
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let script_name = script.display().to_string();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                warn!("{}: {}", script_name, line);
+            }
+        });
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let limit = self.max_output_size as u64;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let read = stdout.take(limit + 1).read_to_end(&mut output);
+            let _ = sender.send(read.map(|_| output));
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let output = match receiver.recv_timeout(self.timeout) {
+            Ok(output) => output,
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CgiError::TimedOut);
+            }
+        };
+        let output = match output {
+            Ok(output) if output.len() as u64 > limit => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CgiError::TooLarge);
+            }
+            output => output?,
+        };
+
+        // The script closed stdout, but it may still be running.
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CgiError::TimedOut);
+            }
+            thread::sleep(WAIT_INTERVAL);
+        };
+        if !status.success() && output.is_empty() {
+            return Err(CgiError::Failed(status.to_string()));
+        }
+        parse_output(output)
+    }
+}
+
+// RFC 3875 §4.1. Request headers become HTTP_* variables, apart from
+// Content-Length and -Type, which have their own, and Proxy, which
+// scripts would take for the HTTP_PROXY setting of their HTTP clients.
+fn environment(
+    request: &Request,
+    route: &Route,
+    script: &Path,
+    name: &str,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let query = request.target().split_once('?').map_or("", |(_, q)| q);
+    let host = request.headers().get("Host").unwrap_or("");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, port),
+        _ => (host, "80"),
+    };
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+        ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method().as_str().to_string()),
+        ("REQUEST_URI", request.target().to_string()),
+        ("QUERY_STRING", query.to_string()),
+        (
+            "SCRIPT_NAME",
+            format!("{}/{}", route.0.trim_end_matches('/'), name),
+        ),
+        ("SCRIPT_FILENAME", script.display().to_string()),
+        ("PATH_INFO", path_info.to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+
+    if let Some(addr) = request.remote_addr() {
+        env.push(("REMOTE_ADDR".to_string(), addr.ip().to_string()));
+        env.push(("REMOTE_PORT".to_string(), addr.port().to_string()));
+    }
+    if !request.body().is_empty() {
+        env.push((
+            "CONTENT_LENGTH".to_string(),
+            request.body().len().to_string(),
+        ));
+    }
+    if let Some(content_type) = request.headers().get("Content-Type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+    if let Some(path) = std::env::var_os("PATH") {
+        env.push(("PATH".to_string(), path.to_string_lossy().into_owned()));
+    }
+
+    for (name, value) in request.headers().iter() {
+        let skipped = ["Content-Length", "Content-Type", "Proxy"];
+        if skipped.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        // Repeated headers are joined the way HTTP allows.
+        match env.iter_mut().find(|(n, _)| *n == var) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((var, value.to_string())),
+        }
+    }
+    env
+}
+
+impl<H: Handler> Handler for CgiHandler<H> {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let path = normalize_path(request.path());
+        let script = match path.as_deref().and_then(|path| self.script_for(path)) {
+            Some(script) => script,
+            None => return self.handler.handle_request(request),
+        };
+
+        match self.run(request, script) {
+            Ok(response) => response,
+            Err(e) => {
+                let status = e.status_code();
+                if status.is_server_error() {
+                    warn!("Failed to run CGI script {}: {}", request.path(), e);
+                }
+                Response::new(status, None)
+            }
+        }
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.handler.handle_bad_request(e)
+    }
+
+    fn handle_panic(&self) -> Response {
+        self.handler.handle_panic()
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// RFC 3875 §6: header lines, a blank line, then the body. Status sets the
+// status code, and a Location without one makes a 302 redirect.
+fn parse_output(mut output: Vec<u8>) -> Result<Response, CgiError> {
+    let (head_len, separator_len) = [&b"\r\n\r\n"[..], &b"\n\n"[..]]
+        .iter()
+        .filter_map(|separator| {
+            output
+                .windows(separator.len())
+                .position(|w| w == *separator)
+                .map(|at| (at, separator.len()))
+        })
+        .min()
+        .ok_or(CgiError::Invalid)?;
+    let body = output.split_off(head_len + separator_len);
+    output.truncate(head_len);
+    let head = String::from_utf8(output).map_err(|_| CgiError::Invalid)?;
+
+    let mut status = None;
+    let mut response = Response::builder();
+    let mut redirect = false;
+    let mut has_content_type = false;
+    for line in head.lines() {
+        let (name, value) = parse_header(line).map_err(|_| CgiError::Invalid)?;
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split(' ').next().unwrap_or("");
+            let code = code
+                .parse::<u16>()
+                .ok()
+                .and_then(|code| StatusCode::try_from(code).ok())
+                .ok_or(CgiError::Invalid)?;
+            status = Some(code);
+            continue;
+        }
+        // The server decides how the body is framed.
+        if ["Content-Length", "Transfer-Encoding", "Connection"]
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        redirect |= name.eq_ignore_ascii_case("Location");
+        has_content_type |= name.eq_ignore_ascii_case("Content-Type");
+        response = response.header(name.to_string(), value.to_string());
+    }
+    if !has_content_type && !redirect && status.is_none() {
+        return Err(CgiError::Invalid);
+    }
+
+    let status = status.unwrap_or(if redirect {
+        StatusCode::Found
+    } else {
+        StatusCode::Ok
+    });
+    Ok(response.status(status).body(body))
+}
+
+#[derive(Debug)]
+enum CgiError {
+    NotFound,
+    Forbidden,
+    Io(io::Error),
+    TimedOut,
+    TooLarge,
+    /// The script exited unsuccessfully without writing anything.
+    Failed(String),
+    /// The script wrote something that is not a CGI response.
+    Invalid,
+}
+
+impl CgiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NotFound,
+            Self::Forbidden => StatusCode::Forbidden,
+            Self::TimedOut => StatusCode::GatewayTimeout,
+            _ => StatusCode::BadGateway,
+        }
+    }
+}
+
+impl From<io::Error> for CgiError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ResolveError> for CgiError {
+    fn from(e: ResolveError) -> Self {
+        match e {
+            ResolveError::Forbidden => Self::Forbidden,
+            ResolveError::Invalid | ResolveError::NotFound => Self::NotFound,
+        }
+    }
+}
+
+impl Display for CgiError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::NotFound => write!(f, "no such script"),
+            Self::Forbidden => write!(f, "forbidden"),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::TooLarge => write!(f, "too much output"),
+            Self::Failed(status) => write!(f, "{}", status),
+            Self::Invalid => write!(f, "invalid output"),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn script_dir(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cgi_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in scripts {
+            let path = dir.join(file);
+            fs::write(&path, source).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    }
+
+    fn call(handler: &impl Handler, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        request.set_remote_addr("192.0.2.7:4000".parse().unwrap());
+        let body = raw.split("\r\n\r\n").nth(1).unwrap_or("");
+        request.set_body(body.as_bytes().to_vec());
+        handler.handle_request(&mut request)
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body().as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    fn not_cgi(_: &mut Request) -> Response {
+        Response::builder().body("static")
+    }
+
+    #[test]
+    fn passes_the_request_to_the_script() {
+        let dir = script_dir(
+            "env",
+            &[(
+                "env",
+                concat!(
+                    "#!/bin/sh\n",
+                    "printf 'Content-Type: text/plain\\r\\nX-Script: yes\\r\\n\\r\\n'\n",
+                    "echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING\"\n",
+                    "echo \"$CONTENT_LENGTH $CONTENT_TYPE $HTTP_X_CUSTOM $REMOTE_ADDR\"\n",
+                    "echo \"${HTTP_PROXY:-none} $(cat)\"\n",
+                ),
+            )],
+        );
+        let handler = CgiHandler::new(not_cgi).route("/cgi-bin/", &dir);
+
+        let response = call(
+            &handler,
+            concat!(
+                "POST /cgi-bin/env/a/b?x=1&y=2 HTTP/1.1\r\nHost: localhost:8080\r\n",
+                "Content-Type: text/plain\r\nX-Custom: 1\r\nProxy: evil\r\n",
+                "Content-Length: 5\r\n\r\nhello",
+            ),
+        );
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(response.headers().get("X-Script"), Some("yes"));
+        assert_eq!(
+            body(&response),
+            "POST /cgi-bin/env /a/b x=1&y=2\n5 text/plain 1 192.0.2.7\nnone hello\n"
+        );
+
+        for path in ["//cgi-bin/env", "/./cgi-bin/env", "/%2Fcgi-bin/env"].iter() {
+            let response = call(&handler, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert_eq!(response.headers().get("X-Script"), Some("yes"), "{}", path);
+        }
+        let response = call(&handler, "GET /index.html HTTP/1.1\r\n\r\n");
+        assert_eq!(body(&response), "static");
+        let response = call(&handler, "GET /cgi-binary HTTP/1.1\r\n\r\n");
+        assert_eq!(body(&response), "static");
+        let response = call(&handler, "GET /cgi-bin/missing HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+
+        let handler = CgiHandler::new(not_cgi).route("/cgi-bin", &dir);
+        let response = call(&handler, "GET /cgi-bin/env/a HTTP/1.1\r\n\r\n");
+        assert!(body(&response).starts_with("GET /cgi-bin/env /a \n"));
+    }
+
+    #[test]
+    fn honours_status_and_location() {
+        let dir = script_dir(
+            "status",
+            &[
+                (
+                    "gone",
+                    "#!/bin/sh\nprintf 'Status: 410 Gone\\nContent-Type: text/plain\\n\\nbye'\n",
+                ),
+                ("moved", "#!/bin/sh\nprintf 'Location: /elsewhere\\n\\n'\n"),
+                ("garbage", "#!/bin/sh\necho not a header block\n"),
+            ],
+        );
+        let handler = CgiHandler::new(not_cgi).route("/cgi-bin/", &dir);
+
+        let response = call(&handler, "GET /cgi-bin/gone HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Gone);
+        assert_eq!(body(&response), "bye");
+
+        let response = call(&handler, "GET /cgi-bin/moved HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Found);
+        assert_eq!(response.headers().get("Location"), Some("/elsewhere"));
+
+        let response = call(&handler, "GET /cgi-bin/garbage HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn enforces_timeout_and_output_limit() {
+        let dir = script_dir(
+            "limits",
+            &[
+                ("slow", "#!/bin/sh\nexec sleep 5\n"),
+                ("lingering", "#!/bin/sh\nexec >&-\nexec sleep 5\n"),
+                (
+                    "chatty",
+                    "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nexec yes\n",
+                ),
+            ],
+        );
+        let handler = CgiHandler::new(not_cgi)
+            .route("/cgi-bin/", &dir)
+            .timeout(Duration::from_millis(200))
+            .max_output_size(1024);
+
+        let response = call(&handler, "GET /cgi-bin/slow HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::GatewayTimeout);
+        let response = call(&handler, "GET /cgi-bin/lingering HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::GatewayTimeout);
+        let response = call(&handler, "GET /cgi-bin/chatty HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+    }
+}
@@ -0,0 +1,696 @@
+use super::cgi::DEFAULT_CGI_TIMEOUT;
+use super::connection::{DEFAULT_KEEP_ALIVE_TIMEOUT, DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_REQUESTS};
+use super::http::StatusCode;
+use super::log::Level;
+use super::server::DEFAULT_SHUTDOWN_TIMEOUT;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+pub const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+  -c, --config <FILE>              Read settings from a TOML file
+  -l, --listen <ADDR>              Address to listen on; repeat for several
+  -p, --public-path <DIR>          Directory to serve files from
+  -w, --workers <N>                Number of worker threads
+      --keep-alive-timeout <SECS>  How long idle connections are kept open
+      --max-body-size <BYTES>      Largest request body accepted
+      --max-requests <N>           Requests served per connection
+      --shutdown-timeout <SECS>    How long to let requests finish on shutdown
+      --log-level <LEVEL>          error, warn, info or debug
+      --tls-cert <FILE>            PEM certificate chain; enables HTTPS
+      --tls-key <FILE>             PEM private key
+      --redirect-http <ADDR>       Redirect plain HTTP on ADDR to HTTPS
+      --error-page <STATUS=FILE>   Page under the public path sent with a status;
+                                   repeat for several
+      --proxy <PREFIX=ADDR>        Forward paths under PREFIX to the HTTP server
+                                   at ADDR; repeat for several
+      --htpasswd <PREFIX=FILE>     Require a user from an htpasswd file for
+                                   paths under PREFIX; repeat for several
+      --tokens <PREFIX=FILE>       Accept bearer tokens from FILE, one per line,
+                                   for paths under PREFIX; repeat for several
+      --autoindex                  List directories without an index.html
+      --templates                  Render *.tmpl files as templates
+      --template-data <DIR>        Directory of JSON files for templates
+      --cgi                        Run the scripts in <public path>/cgi-bin
+                                   for paths under /cgi-bin/
+      --cgi-timeout <SECS>         How long a CGI script may run
+      --check-config               Validate the configuration and exit
+  -h, --help                       Print this help
+
+Settings are taken from, in increasing order of precedence: built-in
+defaults, the config file, the PUBLIC_PATH, TLS_CERT, TLS_KEY and
+HTTP_REDIRECT_ADDR environment variables, and command-line options.
+";
+
+// Settings from one source. Anything left unset falls through to the
+// sources below it, ending at the built-in defaults.
+//
+//   listen = ["127.0.0.1:8080", "[::1]:8080"]
+//   public_path = "public"
+//   workers = 8
+//   keep_alive_timeout = 5
+//   log_level = "info"
+//   templates = true
+//   template_data = "reports/data"
+//   cgi = true
+//
+//   [tls]
+//   cert = "fullchain.pem"
+//   key = "privkey.pem"
+//   redirect_http = "127.0.0.1:8081"
+//
+//   [error_pages]
+//   404 = "errors/404.html"
+//
+//   [proxy]
+//   "/api/" = "127.0.0.1:8998"
+//
+//   [auth."/reports/"]
+//   htpasswd = "users.htpasswd"
+//   tokens = "tokens.txt"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    listen: Option<Vec<String>>,
+    public_path: Option<PathBuf>,
+    workers: Option<usize>,
+    /// In seconds.
+    keep_alive_timeout: Option<u64>,
+    max_body_size: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    /// In seconds.
+    shutdown_timeout: Option<u64>,
+    log_level: Option<String>,
+    autoindex: Option<bool>,
+    templates: Option<bool>,
+    template_data: Option<PathBuf>,
+    cgi: Option<bool>,
+    /// In seconds.
+    cgi_timeout: Option<u64>,
+    #[serde(default)]
+    tls: TlsSettings,
+    #[serde(default)]
+    error_pages: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    proxy: BTreeMap<String, String>,
+    #[serde(default)]
+    auth: BTreeMap<String, AuthSettings>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthSettings {
+    htpasswd: Option<PathBuf>,
+    tokens: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSettings {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    redirect_http: Option<String>,
+}
+
+impl Settings {
+    /// Read a config file. Relative paths in it are relative to the file,
+    /// except error pages, which are always under the public path.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let mut settings: Settings =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        let rebase = |p: &mut PathBuf| {
+            if p.is_relative() {
+                *p = base.join(&*p);
+            }
+        };
+        let paths = settings
+            .public_path
+            .iter_mut()
+            .chain(settings.tls.cert.iter_mut())
+            .chain(settings.tls.key.iter_mut())
+            .chain(settings.template_data.iter_mut())
+            .chain(
+                settings
+                    .auth
+                    .values_mut()
+                    .flat_map(|auth| auth.htpasswd.iter_mut().chain(auth.tokens.iter_mut())),
+            );
+        paths.for_each(rebase);
+
+        Ok(settings)
+    }
+
+    /// Settings from the environment variables the server has always read.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            public_path: var("PUBLIC_PATH").map(PathBuf::from),
+            tls: TlsSettings {
+                cert: var("TLS_CERT").map(PathBuf::from),
+                key: var("TLS_KEY").map(PathBuf::from),
+                redirect_http: var("HTTP_REDIRECT_ADDR"),
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Layer `other` on top of these settings: whatever it sets wins.
+    pub fn merge(self, other: Settings) -> Self {
+        let mut error_pages = self.error_pages;
+        error_pages.extend(other.error_pages);
+        let mut proxy = self.proxy;
+        proxy.extend(other.proxy);
+        let mut auth = self.auth;
+        for (prefix, other) in other.auth {
+            let auth = auth.entry(prefix).or_default();
+            auth.htpasswd = other.htpasswd.or(auth.htpasswd.take());
+            auth.tokens = other.tokens.or(auth.tokens.take());
+        }
+
+        Self {
+            listen: other.listen.or(self.listen),
+            public_path: other.public_path.or(self.public_path),
+            workers: other.workers.or(self.workers),
+            keep_alive_timeout: other.keep_alive_timeout.or(self.keep_alive_timeout),
+            max_body_size: other.max_body_size.or(self.max_body_size),
+            max_requests_per_connection: other
+                .max_requests_per_connection
+                .or(self.max_requests_per_connection),
+            shutdown_timeout: other.shutdown_timeout.or(self.shutdown_timeout),
+            log_level: other.log_level.or(self.log_level),
+            autoindex: other.autoindex.or(self.autoindex),
+            templates: other.templates.or(self.templates),
+            template_data: other.template_data.or(self.template_data),
+            cgi: other.cgi.or(self.cgi),
+            cgi_timeout: other.cgi_timeout.or(self.cgi_timeout),
+            tls: TlsSettings {
+                cert: other.tls.cert.or(self.tls.cert),
+                key: other.tls.key.or(self.tls.key),
+                redirect_http: other.tls.redirect_http.or(self.tls.redirect_http),
+            },
+            error_pages,
+            proxy,
+            auth,
+        }
+    }
+}
+
+// What the command line asked for.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub check_config: bool,
+    pub help: bool,
+    pub settings: Settings,
+}
+
+impl Args {
+    /// Parse the arguments after the program name. Options take their value
+    /// either as the next argument or after '=', as in `--workers=4`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag)))
+            };
+            let settings = &mut parsed.settings;
+
+            match flag.as_str() {
+                "-c" | "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "-l" | "--listen" => settings.listen.get_or_insert_with(Vec::new).push(value()?),
+                "-p" | "--public-path" => settings.public_path = Some(PathBuf::from(value()?)),
+                "-w" | "--workers" => settings.workers = Some(parse_number(&flag, &value()?)?),
+                "--keep-alive-timeout" => {
+                    settings.keep_alive_timeout = Some(parse_number(&flag, &value()?)?)
+                }
+                "--max-body-size" => settings.max_body_size = Some(parse_number(&flag, &value()?)?),
+                "--max-requests" => {
+                    settings.max_requests_per_connection = Some(parse_number(&flag, &value()?)?)
+                }
+                "--shutdown-timeout" => {
+                    settings.shutdown_timeout = Some(parse_number(&flag, &value()?)?)
+                }
+                "--log-level" => settings.log_level = Some(value()?),
+                "--tls-cert" => settings.tls.cert = Some(PathBuf::from(value()?)),
+                "--tls-key" => settings.tls.key = Some(PathBuf::from(value()?)),
+                "--redirect-http" => settings.tls.redirect_http = Some(value()?),
+                "--error-page" => {
+                    let page = value()?;
+                    let (status, path) = page.split_once('=').ok_or_else(|| {
+                        ConfigError::Usage(format!(
+                            "--error-page expects STATUS=FILE, got {}",
+                            page
+                        ))
+                    })?;
+                    settings
+                        .error_pages
+                        .insert(status.to_string(), PathBuf::from(path));
+                }
+                "--proxy" => {
+                    let route = value()?;
+                    let (prefix, upstream) = route.split_once('=').ok_or_else(|| {
+                        ConfigError::Usage(format!("--proxy expects PREFIX=ADDR, got {}", route))
+                    })?;
+                    settings
+                        .proxy
+                        .insert(prefix.to_string(), upstream.to_string());
+                }
+                "--htpasswd" | "--tokens" => {
+                    let rule = value()?;
+                    let (prefix, path) = rule.split_once('=').ok_or_else(|| {
+                        ConfigError::Usage(format!("{} expects PREFIX=FILE, got {}", flag, rule))
+                    })?;
+                    let auth = settings.auth.entry(prefix.to_string()).or_default();
+                    if flag == "--htpasswd" {
+                        auth.htpasswd = Some(PathBuf::from(path));
+                    } else {
+                        auth.tokens = Some(PathBuf::from(path));
+                    }
+                }
+                "--autoindex" => settings.autoindex = Some(true),
+                "--templates" => settings.templates = Some(true),
+                "--template-data" => settings.template_data = Some(PathBuf::from(value()?)),
+                "--cgi" => settings.cgi = Some(true),
+                "--cgi-timeout" => settings.cgi_timeout = Some(parse_number(&flag, &value()?)?),
+                "--check-config" => parsed.check_config = true,
+                "-h" | "--help" => parsed.help = true,
+                _ => return Err(ConfigError::Usage(format!("unknown option {}", flag))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Usage(format!("{} expects a number, got {}", flag, value)))
+}
+
+// The validated settings the server runs with.
+#[derive(Debug)]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub public_path: PathBuf,
+    /// None leaves the choice to the server.
+    pub workers: Option<usize>,
+    pub keep_alive_timeout: Duration,
+    pub max_body_size: usize,
+    pub max_requests_per_connection: usize,
+    pub shutdown_timeout: Duration,
+    pub log_level: Level,
+    pub autoindex: bool,
+    pub templates: bool,
+    pub template_data: Option<PathBuf>,
+    /// The directory to run CGI scripts from, when enabled.
+    pub cgi_bin: Option<PathBuf>,
+    pub cgi_timeout: Duration,
+    pub tls: Option<(PathBuf, PathBuf)>,
+    pub redirect_http: Option<String>,
+    pub error_pages: Vec<(StatusCode, PathBuf)>,
+    /// Longest prefix first, so the most specific route wins.
+    pub proxy: Vec<(String, String)>,
+    /// Longest prefix first, like `proxy`.
+    pub auth: Vec<AuthRule>,
+}
+
+// Credentials required for paths under a prefix. At least one file is set.
+#[derive(Debug, PartialEq)]
+pub struct AuthRule {
+    pub prefix: String,
+    pub htpasswd: Option<PathBuf>,
+    pub tokens: Option<PathBuf>,
+}
+
+impl Config {
+    /// Check every setting, reporting all problems at once rather than only
+    /// the first. `default_public_path` is used when none is configured.
+    pub fn from_settings(
+        settings: Settings,
+        default_public_path: PathBuf,
+    ) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+
+        let listen = settings
+            .listen
+            .unwrap_or_else(|| vec![DEFAULT_LISTEN.to_string()]);
+        if listen.is_empty() {
+            problems.push("listen must name at least one address".to_string());
+        }
+        for addr in listen.iter().chain(settings.tls.redirect_http.iter()) {
+            if addr.to_socket_addrs().is_err() {
+                problems.push(format!("{} is not a valid address to listen on", addr));
+            }
+        }
+
+        let public_path = settings.public_path.unwrap_or(default_public_path);
+        if !public_path.is_dir() {
+            problems.push(format!(
+                "public path {} is not a directory",
+                public_path.display()
+            ));
+        }
+
+        if settings.workers == Some(0) {
+            problems.push("workers must be at least 1".to_string());
+        }
+        if settings.keep_alive_timeout == Some(0) {
+            problems.push("keep_alive_timeout must be at least 1 second".to_string());
+        }
+        if settings.max_requests_per_connection == Some(0) {
+            problems.push("max_requests_per_connection must be at least 1".to_string());
+        }
+        if settings.cgi_timeout == Some(0) {
+            problems.push("cgi_timeout must be at least 1 second".to_string());
+        }
+
+        if let Some(dir) = settings.template_data.as_ref().filter(|dir| !dir.is_dir()) {
+            problems.push(format!(
+                "template data directory {} does not exist",
+                dir.display()
+            ));
+        }
+
+        let cgi_bin = settings
+            .cgi
+            .unwrap_or(false)
+            .then(|| public_path.join("cgi-bin"));
+        if let Some(dir) = cgi_bin.as_ref().filter(|dir| !dir.is_dir()) {
+            problems.push(format!("CGI directory {} does not exist", dir.display()));
+        }
+
+        let log_level = match settings.log_level {
+            Some(level) => level.parse().unwrap_or_else(|e| {
+                problems.push(format!("log_level {}: {}", level, e));
+                Level::Info
+            }),
+            None => Level::Info,
+        };
+
+        let tls = match (settings.tls.cert, settings.tls.key) {
+            (Some(cert), Some(key)) => {
+                for file in [&cert, &key] {
+                    if !file.is_file() {
+                        problems.push(format!("TLS file {} does not exist", file.display()));
+                    }
+                }
+                Some((cert, key))
+            }
+            (Some(_), None) => {
+                problems.push("a TLS certificate is set without a key".to_string());
+                None
+            }
+            (None, Some(_)) => {
+                problems.push("a TLS key is set without a certificate".to_string());
+                None
+            }
+            (None, None) => None,
+        };
+        if tls.is_none() && settings.tls.redirect_http.is_some() {
+            problems.push("redirect_http needs a TLS certificate and key".to_string());
+        }
+
+        let mut error_pages = Vec::new();
+        for (status, path) in settings.error_pages {
+            let code = status
+                .parse::<u16>()
+                .ok()
+                .and_then(|n| StatusCode::try_from(n).ok());
+            match code {
+                Some(code) if code.is_client_error() || code.is_server_error() => {
+                    let file = public_path.join(path.strip_prefix("/").unwrap_or(&path));
+                    if !file.is_file() {
+                        problems.push(format!("error page {} does not exist", file.display()));
+                    }
+                    error_pages.push((code, path));
+                }
+                _ => problems.push(format!(
+                    "error page status {} is not a 4xx or 5xx status",
+                    status
+                )),
+            }
+        }
+
+        let mut proxy: Vec<(String, String)> = settings.proxy.into_iter().collect();
+        for (prefix, upstream) in &proxy {
+            if !prefix.starts_with('/') {
+                problems.push(format!("proxy prefix {} does not start with /", prefix));
+            }
+            let port = upstream
+                .rsplit_once(':')
+                .map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                problems.push(format!(
+                    "proxy upstream {} is not a host:port address",
+                    upstream
+                ));
+            }
+        }
+        proxy.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+
+        let mut auth = Vec::new();
+        for (prefix, settings) in settings.auth {
+            if !prefix.starts_with('/') {
+                problems.push(format!("auth prefix {} does not start with /", prefix));
+            }
+            if settings.htpasswd.is_none() && settings.tokens.is_none() {
+                problems.push(format!(
+                    "auth for {} names no htpasswd or tokens file",
+                    prefix
+                ));
+            }
+            for file in settings.htpasswd.iter().chain(settings.tokens.iter()) {
+                if !file.is_file() {
+                    problems.push(format!("auth file {} does not exist", file.display()));
+                }
+            }
+            auth.push(AuthRule {
+                prefix,
+                htpasswd: settings.htpasswd,
+                tokens: settings.tokens,
+            });
+        }
+        auth.sort_by_key(|rule| Reverse(rule.prefix.len()));
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        Ok(Self {
+            listen,
+            public_path,
+            workers: settings.workers,
+            keep_alive_timeout: settings
+                .keep_alive_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT),
+            max_body_size: settings.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            max_requests_per_connection: settings
+                .max_requests_per_connection
+                .unwrap_or(DEFAULT_MAX_REQUESTS),
+            shutdown_timeout: settings
+                .shutdown_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            log_level,
+            autoindex: settings.autoindex.unwrap_or(false),
+            templates: settings.templates.unwrap_or(false),
+            template_data: settings.template_data,
+            cgi_bin,
+            cgi_timeout: settings
+                .cgi_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CGI_TIMEOUT),
+            tls,
+            redirect_http: settings.tls.redirect_http,
+            error_pages,
+            proxy,
+            auth,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Usage(String),
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Usage(message) => write!(f, "{}", message),
+            Self::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            Self::Parse(path, e) => write!(f, "invalid config file {}: {}", path.display(), e),
+            Self::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn args(args: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("config_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_flags() {
+        let parsed = args(&[
+            "-l",
+            "0.0.0.0:80",
+            "--listen=[::]:80",
+            "--workers",
+            "4",
+            "--check-config",
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed.settings.listen,
+            Some(vec!["0.0.0.0:80".to_string(), "[::]:80".to_string()])
+        );
+        assert_eq!(parsed.settings.workers, Some(4));
+        assert!(parsed.check_config);
+
+        assert!(matches!(
+            args(&["--workers", "many"]),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(args(&["--workers"]), Err(ConfigError::Usage(_))));
+        assert!(matches!(args(&["--verbose"]), Err(ConfigError::Usage(_))));
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let dir = temp_dir("precedence");
+        let file = dir.join("server.toml");
+        fs::write(
+            &file,
+            concat!(
+                "public_path = \".\"\nworkers = 2\nlog_level = \"warn\"\n",
+                "[error_pages]\n404 = \"404.html\"\n[proxy]\n\"/api/\" = \"127.0.0.1:8998\"\n",
+                "[auth.\"/reports/\"]\nhtpasswd = \"users\"\n",
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("404.html"), "gone").unwrap();
+        fs::write(dir.join("users"), "").unwrap();
+        fs::write(dir.join("tokens"), "").unwrap();
+        let tokens_flag = format!("--tokens=/reports/={}", dir.join("tokens").display());
+
+        let settings = Settings::from_file(&file)
+            .unwrap()
+            .merge(Settings::from_env(|name| match name {
+                "PUBLIC_PATH" => Some("/nonexistent".to_string()),
+                _ => None,
+            }))
+            .merge(
+                args(&[
+                    "-w",
+                    "8",
+                    "-p",
+                    dir.to_str().unwrap(),
+                    "--proxy=/api/v2/=127.0.0.1:9000",
+                    &tokens_flag,
+                ])
+                .unwrap()
+                .settings,
+            );
+        let config = Config::from_settings(settings, PathBuf::from("/nonexistent")).unwrap();
+
+        assert_eq!(config.public_path, dir);
+        assert_eq!(config.workers, Some(8));
+        assert_eq!(config.log_level, Level::Warn);
+        assert_eq!(config.listen, vec![DEFAULT_LISTEN.to_string()]);
+        assert_eq!(
+            config.error_pages,
+            vec![(StatusCode::NotFound, PathBuf::from("404.html"))]
+        );
+        assert_eq!(
+            config.proxy,
+            vec![
+                ("/api/v2/".to_string(), "127.0.0.1:9000".to_string()),
+                ("/api/".to_string(), "127.0.0.1:8998".to_string()),
+            ]
+        );
+        assert_eq!(
+            config.auth,
+            vec![AuthRule {
+                prefix: "/reports/".to_string(),
+                htpasswd: Some(dir.join("users")),
+                tokens: Some(dir.join("tokens")),
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let dir = temp_dir("invalid");
+        let file = dir.join("server.toml");
+
+        fs::write(&file, "workers = \"two\"\n").unwrap();
+        assert!(matches!(
+            Settings::from_file(&file),
+            Err(ConfigError::Parse(..))
+        ));
+        fs::write(&file, "port = 80\n").unwrap();
+        assert!(matches!(
+            Settings::from_file(&file),
+            Err(ConfigError::Parse(..))
+        ));
+
+        fs::write(
+            &file,
+            "listen = [\"nowhere\"]\nworkers = 0\nlog_level = \"loud\"\ncgi = true\n[tls]\ncert = \"cert.pem\"\n[error_pages]\n200 = \"ok.html\"\n",
+        )
+        .unwrap();
+        let settings = Settings::from_file(&file).unwrap();
+        match Config::from_settings(settings, dir) {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 6, "{:?}", problems),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// Tests every server runs, so the blocking and async servers keep behaving
+// the same. A server under test answers with `respond`, caps bodies at
+// MAX_BODY_SIZE, and is handed to `check` as a function that starts it.
+use crate::http::{Method, Request, Response};
+use crate::shutdown::ShutdownHandle;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const MAX_BODY_SIZE: usize = 64;
+
+pub fn respond(request: &mut Request) -> Response {
+    match (request.method(), request.path()) {
+        (Method::POST, "/echo") => Response::builder().body(request.body().to_vec()),
+        (_, "/panic") => panic!("handler bug"),
+        (_, "/chunked") => Response::builder().chunked(&b"streamed"[..]),
+        (_, "/slow") => {
+            thread::sleep(Duration::from_millis(200));
+            Response::builder().body("done")
+        }
+        (_, path) => Response::builder().body(format!("path {}", path)),
+    }
+}
+
+/// An address that was free a moment ago.
+pub fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Send raw bytes and read until the server closes the connection.
+pub fn exchange(addr: &str, raw: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(raw.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+pub fn get(addr: &str, path: &str) -> String {
+    exchange(
+        addr,
+        &format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        ),
+    )
+}
+
+/// Start a server on a fresh address with `start`, run every check against
+/// it, and shut it down, making sure it drains a busy connection.
+pub fn check(start: impl Fn(String) -> (ShutdownHandle, JoinHandle<io::Result<()>>)) {
+    let addr = free_addr();
+    let (shutdown, running) = start(addr.clone());
+    thread::sleep(Duration::from_millis(50));
+
+    serves_keep_alive_and_pipelined_requests(&addr);
+    answers_head_without_body(&addr);
+    streams_bodies_of_unknown_length(&addr);
+    reads_request_bodies(&addr);
+    rejects_bad_requests(&addr);
+    answers_panics_with_500(&addr);
+    shuts_down_gracefully(&addr, shutdown, running);
+}
+
+fn serves_keep_alive_and_pipelined_requests(addr: &str) {
+    let response = exchange(
+        addr,
+        "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    assert!(response.contains("path /a"));
+    assert!(response.ends_with("Connection: close\r\nContent-Length: 7\r\n\r\npath /b"));
+}
+
+fn answers_head_without_body(addr: &str) {
+    let response = exchange(addr, "HEAD /a HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("Content-Length: 7\r\n\r\n"));
+}
+
+fn streams_bodies_of_unknown_length(addr: &str) {
+    let response = get(addr, "/chunked");
+    assert!(response.ends_with("Transfer-Encoding: chunked\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+}
+
+fn reads_request_bodies(addr: &str) {
+    let response = exchange(
+        addr,
+        "POST /echo HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+    );
+    assert!(response.ends_with("\r\n\r\nhello"));
+
+    let response = exchange(
+        addr,
+        concat!(
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n",
+            "Expect: 100-continue\r\nConnection: close\r\n\r\n",
+            "3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        ),
+    );
+    assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nabcde"));
+}
+
+fn rejects_bad_requests(addr: &str) {
+    let response = exchange(addr, "NONSENSE\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+
+    let response = exchange(
+        addr,
+        &format!(
+            "POST /echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        ),
+    );
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+}
+
+fn answers_panics_with_500(addr: &str) {
+    let response = exchange(addr, "GET /panic HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(!response.contains("path /a"));
+
+    // The server keeps serving other connections.
+    assert!(get(addr, "/a").ends_with("path /a"));
+}
+
+fn shuts_down_gracefully(
+    addr: &str,
+    shutdown: ShutdownHandle,
+    running: JoinHandle<io::Result<()>>,
+) {
+    // An idle keep-alive connection must not hold up the shutdown, and a
+    // busy one must get its response.
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let slow = {
+        let addr = addr.to_string();
+        thread::spawn(move || exchange(&addr, "GET /slow HTTP/1.1\r\n\r\n"))
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    shutdown.shutdown();
+    let response = slow.join().unwrap();
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(response.ends_with("done"));
+    running.join().unwrap().unwrap();
+    assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+}
@@ -0,0 +1,397 @@
+use crate::http::{ChunkedDecoder, Headers, ParseError};
+use rustls::{ServerConnection, StreamOwned};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const READ_CHUNK_SIZE: usize = 4096;
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_REQUESTS: usize = 100;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub max_body_size: usize,
+    /// How long an idle persistent connection is kept open waiting for the next request.
+    pub keep_alive_timeout: Duration,
+    /// How many requests are served on one connection before it is closed.
+    pub max_requests: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            max_requests: DEFAULT_MAX_REQUESTS,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The peer closed the connection before sending anything.
+    Closed,
+    /// No data arrived within the stream's read timeout.
+    TimedOut,
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        // Platforms disagree on which kind a read timeout produces.
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e)
+    }
+}
+
+// A stream a server can serve connections on.
+pub trait Transport: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Wait up to `timeout` for the peer to send something or hang up, and
+    /// say whether it did. Nothing is consumed.
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool>;
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        peek_readable(self, timeout)
+    }
+}
+
+impl Transport for StreamOwned<ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    // Records that were already received may hold data rustls has not
+    // handed out yet, which the socket knows nothing about.
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        let state = self
+            .conn
+            .process_new_packets()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if state.plaintext_bytes_to_read() > 0 || state.peer_has_closed() {
+            return Ok(true);
+        }
+        peek_readable(&self.sock, timeout)
+    }
+}
+
+fn peek_readable(sock: &TcpStream, timeout: Duration) -> io::Result<bool> {
+    let previous = sock.read_timeout()?;
+    // A zero timeout would mean waiting forever.
+    sock.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    let readable = match sock.peek(&mut [0; 1]) {
+        Ok(_) => Ok(true),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(false),
+        Err(e) => Err(e),
+    };
+    sock.set_read_timeout(previous)?;
+    readable
+}
+
+// Buffers bytes read from a stream so a request can be taken apart into its
+// head and body, whatever sizes the underlying reads happen to return.
+// Bytes past the end of one request stay buffered for the next, which is
+// what makes pipelined requests on a persistent connection work.
+pub struct Connection<S> {
+    stream: S,
+    buf: Vec<u8>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            peer_addr: None,
+        }
+    }
+
+    /// Remember the address of the other end, for `Request::remote_addr`.
+    pub fn with_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// The bytes already read that no request has taken yet.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// The stream, and the bytes already read from it that no request has
+    /// taken yet.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    /// Read until a complete request line and header block has arrived,
+    /// and return those bytes including the terminating empty line.
+    pub fn read_head(&mut self) -> Result<Vec<u8>, ReadError> {
+        loop {
+            if let Some(head) = split_head(&mut self.buf)? {
+                return Ok(head);
+            }
+            if self.fill()? == 0 {
+                return Err(closed_early(&self.buf));
+            }
+        }
+    }
+
+    /// Read the body that follows a request head, as framed by its
+    /// `Transfer-Encoding` and `Content-Length` headers.
+    pub fn read_body(&mut self, headers: &Headers, limits: &Limits) -> Result<Vec<u8>, ReadError> {
+        let length = body_length(headers, limits)?;
+        if length != BodyLength::Empty && expects_continue(headers) {
+            self.stream.write_all(CONTINUE.as_bytes())?;
+        }
+
+        let mut chunked = ChunkedDecoder::new();
+        loop {
+            if let Some(body) = split_body(&mut self.buf, length, &mut chunked, limits)? {
+                return Ok(body);
+            }
+            if self.fill()? == 0 {
+                return Err(length.cut_short().into());
+            }
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let n = self.stream.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Transport> Connection<S> {
+    /// Wait up to `timeout` for the start of the next request, which may
+    /// already be buffered, and say whether it has arrived.
+    pub fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        if !self.buf.is_empty() {
+            return Ok(true);
+        }
+        self.stream.wait_readable(timeout)
+    }
+}
+
+// The rest of this file takes requests apart as their bytes arrive, without
+// doing any I/O itself, so the async server can share it with `Connection`.
+
+// Clients that sent `Expect: 100-continue` wait for this before sending the body.
+pub const CONTINUE: &str = "HTTP/1.1 100 Continue\r\n\r\n";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+}
+
+impl BodyLength {
+    /// The error for a body whose connection closed before it was complete.
+    pub fn cut_short(self) -> ParseError {
+        match self {
+            Self::Chunked => ParseError::InvalidChunk,
+            _ => ParseError::InvalidRequest,
+        }
+    }
+}
+
+/// Take a complete request head off the front of `buf`, if it has arrived.
+pub fn split_head(buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ParseError> {
+    if let Some(i) = find(buf, b"\r\n\r\n") {
+        return Ok(Some(buf.drain(..i + 4).collect()));
+    }
+    if buf.len() > MAX_HEAD_SIZE {
+        return Err(ParseError::HeadTooLarge);
+    }
+    Ok(None)
+}
+
+/// Take a complete body of the given length off the front of `buf`, if it
+/// has arrived. `chunked` keeps a chunked body's progress between calls.
+pub fn split_body(
+    buf: &mut Vec<u8>,
+    length: BodyLength,
+    chunked: &mut ChunkedDecoder,
+    limits: &Limits,
+) -> Result<Option<Vec<u8>>, ParseError> {
+    match length {
+        BodyLength::Empty => Ok(Some(Vec::new())),
+        BodyLength::Fixed(len) if buf.len() < len => Ok(None),
+        BodyLength::Fixed(len) => Ok(Some(buf.drain(..len).collect())),
+        BodyLength::Chunked => chunked.decode(buf, limits.max_body_size),
+    }
+}
+
+/// The error for a connection that closed before a whole head arrived,
+/// given what had arrived of it.
+pub fn closed_early(buf: &[u8]) -> ReadError {
+    if buf.is_empty() {
+        ReadError::Closed
+    } else {
+        ParseError::InvalidRequest.into()
+    }
+}
+
+pub fn expects_continue(headers: &Headers) -> bool {
+    headers
+        .get("Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+}
+
+// RFC 7230 §3.3.3. A request carrying both Transfer-Encoding and
+// Content-Length is rejected outright, since the two disagreeing is a
+// classic request smuggling vector.
+pub fn body_length(headers: &Headers, limits: &Limits) -> Result<BodyLength, ParseError> {
+    let transfer_encoding = headers.get_all("Transfer-Encoding").last();
+    let mut content_lengths = headers.get_all("Content-Length");
+
+    if let Some(encoding) = transfer_encoding {
+        if headers.contains("Content-Length") {
+            return Err(ParseError::InvalidContentLength);
+        }
+
+        let last = encoding.rsplit(',').next().unwrap_or("").trim();
+        return if last.eq_ignore_ascii_case("chunked") {
+            Ok(BodyLength::Chunked)
+        } else {
+            Err(ParseError::InvalidTransferEncoding)
+        };
+    }
+
+    let len = match content_lengths.next() {
+        Some(len) => len,
+        None => return Ok(BodyLength::Empty),
+    };
+    if content_lengths.any(|other| other != len) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::InvalidContentLength);
+    }
+
+    match len.parse() {
+        Ok(0) => Ok(BodyLength::Empty),
+        Ok(len) if len > limits.max_body_size => Err(ParseError::BodyTooLarge),
+        Ok(len) => Ok(BodyLength::Fixed(len)),
+        // Too big for usize is certainly too big for us.
+        Err(_) => Err(ParseError::BodyTooLarge),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Request;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    fn read(raw: &[u8], limits: &Limits) -> Result<(Vec<u8>, Vec<u8>), ReadError> {
+        let mut connection = Connection::new(Cursor::new(raw.to_vec()));
+        let head = connection.read_head()?;
+        let request = Request::try_from(&head[..])?;
+        let body = connection.read_body(request.headers(), limits)?;
+        Ok((head, body))
+    }
+
+    #[test]
+    fn reads_content_length_body() {
+        let raw = b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let (_, body) = read(raw, &Limits::default()).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn refuses_huge_chunk_sizes() {
+        let raw =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        match read(raw, &Limits::default()) {
+            Err(ReadError::Parse(ParseError::BodyTooLarge)) => {}
+            other => panic!("expected BodyTooLarge, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let raw =
+            b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let (_, body) = read(raw, &Limits::default()).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn enforces_max_body_size() {
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let raw = b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+
+        match read(raw, &limits) {
+            Err(ReadError::Parse(ParseError::BodyTooLarge)) => {}
+            other => panic!("expected BodyTooLarge, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn keeps_pipelined_requests_apart() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n";
+        let mut connection = Connection::new(Cursor::new(raw.to_vec()));
+
+        let head = connection.read_head().unwrap();
+        let request = Request::try_from(&head[..]).unwrap();
+        assert_eq!(request.path(), "/a");
+        let body = connection
+            .read_body(request.headers(), &Limits::default())
+            .unwrap();
+        assert_eq!(body, b"abc");
+
+        let head = connection.read_head().unwrap();
+        let request = Request::try_from(&head[..]).unwrap();
+        assert_eq!(request.path(), "/b");
+
+        assert!(matches!(connection.read_head(), Err(ReadError::Closed)));
+    }
+
+    #[test]
+    fn rejects_conflicting_lengths() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert!(read(raw, &Limits::default()).is_err());
+
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(read(raw, &Limits::default()).is_err());
+    }
+}
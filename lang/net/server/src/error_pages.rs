@@ -0,0 +1,192 @@
+use super::escape;
+use super::http::{mime, ParseError, Request, Response, StatusCode};
+use super::server::Handler;
+use std::fs;
+use std::path::PathBuf;
+
+// Gives 4xx and 5xx responses a body, instead of leaving the browser to
+// show an empty page:
+//
+//   ErrorPages::new(handler, public_path)
+//       .page(StatusCode::NotFound, "404.html")
+//
+// A configured page is read from under the root on every use, so it can be
+// edited without a restart. Clients that ask for JSON in Accept get
+// {"status":404,"error":"Not Found"} instead, and every other error a small
+// built-in page. Responses the handler already gave a body are left alone.
+pub struct ErrorPages<H> {
+    handler: H,
+    root: PathBuf,
+    pages: Vec<(StatusCode, String)>,
+}
+
+impl<H: Handler> ErrorPages<H> {
+    pub fn new(handler: H, root: impl Into<PathBuf>) -> Self {
+        Self {
+            handler,
+            root: root.into(),
+            pages: Vec::new(),
+        }
+    }
+
+    /// Send the file at `path`, relative to the root, with `status` responses.
+    pub fn page(mut self, status: StatusCode, path: &str) -> Self {
+        self.pages.push((status, path.to_string()));
+        self
+    }
+
+    fn fill(&self, response: &mut Response, accept: Option<&str>, message: Option<&str>) {
+        let status = response.status_code();
+        if !(status.is_client_error() || status.is_server_error()) || !response.body().is_empty() {
+            return;
+        }
+
+        let (content_type, body) = if mime::prefers_json(accept) {
+            ("application/json".to_string(), json_error(status, message))
+        } else {
+            match self.read_page(status) {
+                Some(page) => page,
+                None => (
+                    "text/html; charset=utf-8".to_string(),
+                    html_error(status, message).into_bytes(),
+                ),
+            }
+        };
+
+        let headers = response.headers_mut();
+        headers.insert("Content-Type", content_type);
+        if !headers.contains_token("Vary", "Accept") {
+            headers.append("Vary", "Accept");
+        }
+        response.set_body(body);
+    }
+
+    fn read_page(&self, status: StatusCode) -> Option<(String, Vec<u8>)> {
+        let (_, path) = self.pages.iter().find(|(code, _)| *code == status)?;
+        let file = self.root.join(path.trim_start_matches('/'));
+
+        match fs::read(&file) {
+            Ok(body) => Some((mime::from_path(path).to_string(), body)),
+            Err(e) => {
+                error!("Failed to read error page {}: {}", file.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl<H: Handler> Handler for ErrorPages<H> {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let mut response = self.handler.handle_request(request);
+        self.fill(&mut response, request.headers().get("Accept"), None);
+        response
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        let mut response = self.handler.handle_bad_request(e);
+        self.fill(&mut response, None, Some(&e.to_string()));
+        response
+    }
+
+    fn handle_panic(&self) -> Response {
+        let mut response = self.handler.handle_panic();
+        self.fill(&mut response, None, None);
+        response
+    }
+}
+
+fn json_error(status: StatusCode, message: Option<&str>) -> Vec<u8> {
+    let message = match message {
+        Some(message) => format!(",\"message\":\"{}\"", escape::json(message)),
+        None => String::new(),
+    };
+    format!(
+        "{{\"status\":{},\"error\":\"{}\"{}}}",
+        status.as_u16(),
+        status.reason_phrase(),
+        message
+    )
+    .into_bytes()
+}
+
+fn html_error(status: StatusCode, message: Option<&str>) -> String {
+    let title = format!("{} {}", status.as_u16(), status.reason_phrase());
+    let message = match message {
+        Some(message) => format!("<p>{}</p>\n", escape::html(message)),
+        None => String::new(),
+    };
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+            "<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n{1}</body>\n</html>\n",
+        ),
+        title, message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::env;
+
+    fn call(handler: &impl Handler, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        handler.handle_request(&mut request)
+    }
+
+    fn text(response: &Response) -> &str {
+        std::str::from_utf8(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    fn not_found(_: &mut Request) -> Response {
+        Response::new(StatusCode::NotFound, None)
+    }
+
+    #[test]
+    fn fills_empty_error_responses() {
+        let root = env::temp_dir().join(format!("error_pages_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("404.html"), "<h1>Nothing here</h1>").unwrap();
+        let handler = ErrorPages::new(not_found, &root).page(StatusCode::NotFound, "/404.html");
+
+        let response = call(&handler, "GET /missing HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(text(&response), "<h1>Nothing here</h1>");
+
+        let response = call(
+            &handler,
+            "GET /missing HTTP/1.1\r\nAccept: application/json\r\n\r\n",
+        );
+        assert_eq!(text(&response), "{\"status\":404,\"error\":\"Not Found\"}");
+
+        let response = handler.handle_bad_request(&ParseError::InvalidHeaderName);
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        assert!(text(&response).contains("<h1>400 Bad Request</h1>\n<p>Invalid Header Name</p>"));
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let handler = ErrorPages::new(
+            |request: &mut Request| match request.path() {
+                "/teapot" => Response::builder()
+                    .status(StatusCode::ImATeapot)
+                    .body("short and stout"),
+                _ => Response::new(StatusCode::NoContent, None),
+            },
+            "/nonexistent",
+        );
+
+        assert_eq!(
+            text(&call(&handler, "GET /teapot HTTP/1.1\r\n\r\n")),
+            "short and stout"
+        );
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers().get("Content-Type"), None);
+    }
+}
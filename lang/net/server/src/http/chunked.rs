@@ -0,0 +1,282 @@
+use super::ParseError;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::str;
+
+// Chunk size lines longer than this are refused rather than buffered.
+const MAX_LINE_LEN: usize = 4096;
+
+// 5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n
+//
+// Decodes a chunked body as it arrives. Each call takes what it can off the
+// front of the buffer and remembers where it got to, so a body that arrives
+// in many reads is only looked at once.
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    body: Vec<u8>,
+    state: State,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode what has arrived in `buf`, returning the body once it is
+    /// complete and leaving whatever follows it in `buf`. Decoding stops
+    /// with `ParseError::BodyTooLarge` once the body would exceed `max_size`.
+    pub fn decode(
+        &mut self,
+        buf: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<Option<Vec<u8>>, ParseError> {
+        let mut pos = 0;
+        let result = self.advance(buf, &mut pos, max_size);
+        buf.drain(..pos);
+        result
+    }
+
+    fn advance(
+        &mut self,
+        buf: &[u8],
+        pos: &mut usize,
+        max_size: usize,
+    ) -> Result<Option<Vec<u8>>, ParseError> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let (line, next) = match next_line(buf, *pos)? {
+                        Some(found) => found,
+                        None => return Ok(None),
+                    };
+                    let size = parse_size(line)?;
+                    if size > max_size.saturating_sub(self.body.len()) {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+                    *pos = next;
+                    self.state = if size == 0 {
+                        State::Trailers
+                    } else {
+                        State::Data(size)
+                    };
+                }
+                State::Data(remaining) => {
+                    let available = remaining.min(buf.len() - *pos);
+                    self.body.extend_from_slice(&buf[*pos..*pos + available]);
+                    *pos += available;
+                    if available < remaining {
+                        self.state = State::Data(remaining - available);
+                        return Ok(None);
+                    }
+                    self.state = State::DataEnd;
+                }
+                State::DataEnd => {
+                    if buf.len() < *pos + 2 {
+                        return Ok(None);
+                    }
+                    if &buf[*pos..*pos + 2] != b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    *pos += 2;
+                    self.state = State::Size;
+                }
+                // The last chunk is followed by optional trailer fields and an
+                // empty line. Trailers are discarded.
+                State::Trailers => {
+                    let (line, next) = match next_line(buf, *pos)? {
+                        Some(found) => found,
+                        None => return Ok(None),
+                    };
+                    *pos = next;
+                    if line.is_empty() {
+                        self.state = State::Size;
+                        return Ok(Some(std::mem::take(&mut self.body)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Reads a chunked body from `inner` as it is read from, so it can be passed
+// on without ever being held whole. The body ends at the last chunk, and
+// whatever follows it is left unread.
+pub struct ChunkedReader<R> {
+    inner: R,
+    // What is left of the current chunk, or None before its size is read.
+    remaining: Option<usize>,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: None,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        (&mut self.inner)
+            .take(MAX_LINE_LEN as u64 + 2)
+            .read_until(b'\n', &mut line)?;
+        match line.strip_suffix(b"\r\n") {
+            Some(line) => Ok(line.to_vec()),
+            None if line.len() > MAX_LINE_LEN => Err(invalid_chunk()),
+            None => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = match self.remaining {
+            Some(remaining) => remaining,
+            None => {
+                let size = parse_size(&self.read_line()?).map_err(|_| invalid_chunk())?;
+                if size == 0 {
+                    // Trailer fields, discarded, up to an empty line.
+                    while !self.read_line()?.is_empty() {}
+                    self.done = true;
+                    return Ok(0);
+                }
+                size
+            }
+        };
+
+        let len = buf.len().min(remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining = Some(remaining - read);
+        if read == remaining {
+            if !self.read_line()?.is_empty() {
+                return Err(invalid_chunk());
+            }
+            self.remaining = None;
+        }
+        Ok(read)
+    }
+}
+
+/// Send everything `reader` holds as a chunked body, one chunk per read.
+pub fn write_chunked(reader: &mut dyn Read, out: &mut impl Write) -> io::Result<()> {
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return out.write_all(b"0\r\n\r\n");
+        }
+        write!(out, "{:x}\r\n", read)?;
+        out.write_all(&buf[..read])?;
+        out.write_all(b"\r\n")?;
+    }
+}
+
+// 1a;name=value → 26. Chunk extensions carry nothing we use.
+fn parse_size(line: &[u8]) -> Result<usize, ParseError> {
+    let size = line.split(|&b| b == b';').next().unwrap_or(line);
+    let size = str::from_utf8(size).map_err(|_| ParseError::InvalidChunk)?;
+    usize::from_str_radix(size.trim(), 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+fn invalid_chunk() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid chunk")
+}
+
+fn next_line(buf: &[u8], start: usize) -> Result<Option<(&[u8], usize)>, ParseError> {
+    match buf[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(i) => Ok(Some((&buf[start..start + i], start + i + 2))),
+        None if buf.len() - start > MAX_LINE_LEN => Err(ParseError::InvalidChunk),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(buf: &[u8], max_size: usize) -> Result<Option<Vec<u8>>, ParseError> {
+        ChunkedDecoder::new().decode(&mut buf.to_vec(), max_size)
+    }
+
+    #[test]
+    fn decodes_complete_body() {
+        let mut buf = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\n\r\nGET".to_vec();
+        let body = ChunkedDecoder::new()
+            .decode(&mut buf, 1024)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(body, b"hello world");
+        assert_eq!(buf, b"GET");
+    }
+
+    #[test]
+    fn waits_for_more_input() {
+        assert!(decode(b"5\r\nhel", 1024).unwrap().is_none());
+        assert!(decode(b"5\r\nhello\r\n0\r\n", 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_and_oversized_chunks() {
+        assert!(decode(b"zz\r\nhello\r\n", 1024).is_err());
+        assert!(decode(b"5\r\nhelloXX", 1024).is_err());
+        assert!(decode(b"5\r\nhello\r\n0\r\n\r\n", 4).is_err());
+        assert!(matches!(
+            decode(b"1\r\na\r\nffffffffffffffff\r\n", 1024),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn decodes_across_reads() {
+        let encoded = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\nGET";
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf = Vec::new();
+        for (i, &byte) in encoded.iter().enumerate() {
+            buf.push(byte);
+            if let Some(body) = decoder.decode(&mut buf, 1024).unwrap() {
+                assert_eq!(body, b"hello world");
+                assert_eq!(i, encoded.len() - 4);
+                return;
+            }
+        }
+        panic!("body never completed");
+    }
+
+    #[test]
+    fn reads_and_writes_streams() {
+        let encoded = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\n\r\nGET";
+        let mut input = &encoded[..];
+        let mut body = Vec::new();
+        ChunkedReader::new(&mut input)
+            .read_to_end(&mut body)
+            .unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(input, b"GET");
+
+        let mut out = Vec::new();
+        write_chunked(&mut &body[..], &mut out).unwrap();
+        assert_eq!(out, b"b\r\nhello world\r\n0\r\n\r\n");
+
+        for bad in [&b"zz\r\nhello\r\n"[..], b"5\r\nhelloXX\r\n", b"5\r\nhel"].iter() {
+            let mut body = Vec::new();
+            assert!(ChunkedReader::new(*bad).read_to_end(&mut body).is_err());
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Sun, 06 Nov 1994 08:49:37 GMT
+//
+// The IMF-fixdate format from RFC 7231 §7.1.1.1, used by the Date,
+// Last-Modified and Expires headers.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// 10/Oct/2000:13:55:36 +0000
+//
+// The timestamp format of the Common Log Format, always in UTC.
+pub fn format_clf_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// Recipients must accept all three formats RFC 7231 §7.1.1.1 allows:
+//   Sun, 06 Nov 1994 08:49:37 GMT    (IMF-fixdate)
+//   Sunday, 06-Nov-94 08:49:37 GMT   (obsolete RFC 850)
+//   Sun Nov  6 08:49:37 1994         (ANSI C asctime)
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (*day, *month, (*year).parse().ok()?, *time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            let year: i64 = year.parse().ok()?;
+            // Two-digit years: 70-99 are 19xx, the rest 20xx.
+            let year = if year < 70 { year + 2000 } else { year + 1900 };
+            (day, month, year, *time)
+        }
+        [_, month, day, time, year] => (*day, *month, (*year).parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+
+    let mut time = time.split(':').map(|n| n.parse::<u64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if day == 0 || day > 31 || hour > 23 || minute > 59 || second > 60 || time.next().is_some() {
+        return None;
+    }
+
+    // All three formats write the year with four digits; anything longer
+    // would overflow the day count.
+    if year > 9999 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Howard Hinnant's days-from-civil algorithm: (year, month, day) to days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+// Howard Hinnant's days-to-civil algorithm: days since 1970-01-01 to (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(format_http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn formats_clf_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(format_clf_date(time), "10/Oct/2000:13:55:36 +0000");
+    }
+
+    #[test]
+    fn parses_all_three_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784_111_777));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_years() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 300000000000 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(
+            parse_http_date("Sun Nov  6 08:49:37 9223372036854775807"),
+            None
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1969 08:49:37 GMT"), None);
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+}
@@ -0,0 +1,97 @@
+// The content codings the server can produce, in the order it prefers them
+// when the client likes several equally.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    /// The token used in Accept-Encoding and Content-Encoding.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// The file extension of a precompressed copy, e.g. `style.css.gz`.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gz"),
+            Self::Deflate => None,
+        }
+    }
+}
+
+// Accept-Encoding: gzip;q=0.8, br, *;q=0
+//
+// Pick the encoding from `available` the client weighs highest (RFC 7231
+// §5.3.4), breaking ties by the order of `available`. None means the body
+// should be sent as is.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut weights = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    value.trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        weights.push((coding, q));
+    }
+
+    let weight = |encoding: &Encoding| {
+        let exact = weights
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()));
+        let any = weights.iter().find(|(coding, _)| *coding == "*");
+        exact.or(any).map(|(_, q)| *q).unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let q = weight(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_by_weight_then_preference() {
+        assert_eq!(
+            negotiate("gzip, deflate, br", &Encoding::ALL),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5", &Encoding::ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("*", &[Encoding::Gzip]), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *;q=0.1", &[Encoding::Brotli]), None);
+        assert_eq!(negotiate("identity", &Encoding::ALL), None);
+        assert_eq!(negotiate("", &Encoding::ALL), None);
+    }
+}
@@ -0,0 +1,103 @@
+use std::borrow::Cow;
+
+// Header names are matched case-insensitively (RFC 7230 §3.2) but stored as
+// received, so a request can be echoed or proxied without changing it.
+// Entries are kept in order in a Vec rather than a HashMap: requests carry a
+// handful of headers and the same name may legitimately appear several times.
+#[derive(Debug, Default, Clone)]
+pub struct Headers<'buf> {
+    entries: Vec<(Cow<'buf, str>, Cow<'buf, str>)>,
+}
+
+impl<'buf> Headers<'buf> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// The first value of the header `name`, if present.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// Every value of the header `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether a comma-separated list header such as `Connection: keep-alive, Upgrade`
+    /// includes `token`, ignoring case.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Add a value, keeping any existing values for the same name.
+    pub fn append(&mut self, name: impl Into<Cow<'buf, str>>, value: impl Into<Cow<'buf, str>>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Set a value, replacing any existing values for the same name.
+    pub fn insert(&mut self, name: impl Into<Cow<'buf, str>>, value: impl Into<Cow<'buf, str>>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_ref(), v.as_ref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn keeps_every_value() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "application/json");
+
+        let values: Vec<_> = headers.get_all("Accept").collect();
+        assert_eq!(values, vec!["text/html", "application/json"]);
+
+        headers.insert("Accept", "*/*");
+        let values: Vec<_> = headers.get_all("Accept").collect();
+        assert_eq!(values, vec!["*/*"]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Method {
     GET,
     DELETE,
@@ -13,6 +14,22 @@ pub enum Method {
     PATCH,
 }
 
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::GET => "GET",
+            Self::DELETE => "DELETE",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::HEAD => "HEAD",
+            Self::CONNECT => "CONNECT",
+            Self::OPTIONS => "OPTIONS",
+            Self::TRACE => "TRACE",
+            Self::PATCH => "PATCH",
+        }
+    }
+}
+
 impl FromStr for Method {
     type Err = MethodError;
 
@@ -0,0 +1,103 @@
+use std::path::Path;
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// Extension → media type for the files a static site typically ships.
+// Text types carry a charset so browsers don't have to sniff it.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+];
+
+/// The media type for a file extension, ignoring case.
+pub fn from_extension(extension: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime)| *mime)
+}
+
+/// The media type for a file, falling back to application/octet-stream.
+pub fn from_path(path: impl AsRef<Path>) -> &'static str {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(from_extension)
+        .unwrap_or(OCTET_STREAM)
+}
+
+// Accept: application/json, text/html;q=0.9
+//
+// JSON is only sent to clients that list it before HTML, so browsers,
+// which list HTML first, keep getting the page.
+pub fn prefers_json(accept: Option<&str>) -> bool {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    accept
+        .split(',')
+        .map(|item| item.split(';').next().unwrap_or("").trim())
+        .find(|media_type| *media_type == "application/json" || *media_type == "text/html")
+        == Some("application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_json_only_when_listed_first() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("application/json;q=0.9, text/html")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,*/*;q=0.8"
+        )));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(None));
+    }
+
+    #[test]
+    fn detects_from_path() {
+        assert_eq!(from_path("index.html"), "text/html; charset=utf-8");
+        assert_eq!(from_path("pkg/game_bg.WASM"), "application/wasm");
+        assert_eq!(from_path("logo.svg"), "image/svg+xml");
+        assert_eq!(from_path("archive.unknown"), OCTET_STREAM);
+        assert_eq!(from_path("Makefile"), OCTET_STREAM);
+    }
+}
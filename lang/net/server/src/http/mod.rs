@@ -1,12 +1,22 @@
+pub use chunked::ChunkedDecoder;
+pub use headers::Headers;
 pub use method::Method;
+#[allow(unused_imports)]
 pub use query_string::{QueryString, Value as QueryStringValue};
 pub use request::ParseError;
 pub use request::Request;
 pub use response::Response;
 pub use status_code::StatusCode;
 
+pub mod chunked;
+pub mod date;
+pub mod encoding;
+pub mod headers;
 pub mod method;
+pub mod mime;
+pub mod percent;
 pub mod query_string;
+pub mod range;
 pub mod request;
 pub mod response;
 pub mod status_code;
@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+
+// hello%20world%21 → hello world!
+//
+// Decode %XX escapes, and '+' as a space when decoding form data.
+// The input is borrowed back unchanged when there is nothing to decode,
+// which is the common case. Malformed escapes are kept literally and
+// invalid UTF-8 is replaced, the way browsers treat them.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Cow<'_, str> {
+    let needs_decoding = s.bytes().any(|b| b == b'%' || (plus_as_space && b == b'+'));
+    if !needs_decoding {
+        return Cow::Borrowed(s);
+    }
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match (bytes.get(i + 1), bytes.get(i + 2)) {
+                (Some(&hi), Some(&lo)) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                    decoded.push(hex_value(hi) << 4 | hex_value(lo));
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(e) => Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+// my file.txt → my%20file.txt
+//
+// Escape everything in a URL path except unreserved characters (RFC 3986
+// §2.3) and the '/' separators, so any file name can be linked to.
+pub fn percent_encode_path(s: &str) -> Cow<'_, str> {
+    let keep = |b: u8| b.is_ascii_alphanumeric() || b"-._~/".contains(&b);
+    if s.bytes().all(keep) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut encoded = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        if keep(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+fn hex_value(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        b'a'..=b'f' => b - b'a' + 10,
+        _ => b - b'A' + 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%21", false), "a b!");
+        assert_eq!(percent_decode("caf%C3%A9", false), "café");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+        assert_eq!(percent_decode("%FF", false), "\u{FFFD}");
+    }
+
+    #[test]
+    fn encodes_paths() {
+        assert_eq!(
+            percent_encode_path("/a b/café?.txt"),
+            "/a%20b/caf%C3%A9%3F.txt"
+        );
+        assert_eq!(
+            percent_decode(&percent_encode_path("100%#"), false),
+            "100%#"
+        );
+        assert!(matches!(
+            percent_encode_path("/dist/app-1.0.js"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn borrows_when_unchanged() {
+        assert!(matches!(
+            percent_decode("plain", true),
+            Cow::Borrowed("plain")
+        ));
+        assert!(matches!(percent_decode("a%20b", true), Cow::Owned(_)));
+    }
+}
@@ -1,45 +1,116 @@
+use super::percent::percent_decode;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::str::FromStr;
 
+// Keys and values borrow from the request buffer unless percent-decoding
+// actually changed them.
 #[derive(Debug)]
 pub struct QueryString<'buf> {
-    data: HashMap<&'buf str, Value<'buf>>,
+    data: HashMap<Cow<'buf, str>, Value<'buf>>,
 }
 
 #[derive(Debug)]
 pub enum Value<'buf> {
-    Single(&'buf str),
-    Multiple(Vec<&'buf str>),
+    Single(Cow<'buf, str>),
+    Multiple(Vec<Cow<'buf, str>>),
+}
+
+impl<'buf> Value<'buf> {
+    /// The first value given for the key.
+    pub fn first(&self) -> &str {
+        match self {
+            Value::Single(val) => val,
+            Value::Multiple(vec) => &vec[0],
+        }
+    }
+
+    /// Every value given for the key, in order.
+    pub fn all(&self) -> Vec<&str> {
+        match self {
+            Value::Single(val) => vec![val.as_ref()],
+            Value::Multiple(vec) => vec.iter().map(|val| val.as_ref()).collect(),
+        }
+    }
 }
 
 impl<'buf> QueryString<'buf> {
-    pub fn get(&self, key: &str) -> Option<&Value> {
+    pub fn get(&self, key: &str) -> Option<&Value<'_>> {
         self.data.get(key)
     }
+
+    /// The first value for `key`, parsed as `T`.
+    ///
+    /// `query_string.get_parsed::<u32>("page")` is `None` when there is no
+    /// page parameter and `Some(Err(..))` when it is not a number.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<Result<T, T::Err>> {
+        self.get(key).map(|val| val.first().parse())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.data.keys().map(|key| key.as_ref())
+    }
 }
 
 // a=1&b=2&c&d=&e===&d=7&d=abc
+//
+// The same parser handles application/x-www-form-urlencoded bodies,
+// which use the same syntax, '+' for spaces included.
 impl<'buf> From<&'buf str> for QueryString<'buf> {
     fn from(s: &'buf str) -> Self {
         let mut data = HashMap::new();
 
-        for sub_str in s.split('&') {
+        for sub_str in s.split('&').filter(|sub_str| !sub_str.is_empty()) {
             let mut key = sub_str;
             let mut val = "";
             if let Some(i) = sub_str.find('=') {
                 key = &sub_str[..i];
                 val = &sub_str[i + 1..];
             }
+            let key = percent_decode(key, true);
+            let val = percent_decode(val, true);
 
-            data.entry(key)
-                .and_modify(|existing: &mut Value| match existing {
+            match data.get_mut(&key) {
+                Some(existing) => match existing {
                     Value::Single(prev_val) => {
+                        let prev_val = std::mem::take(prev_val);
                         *existing = Value::Multiple(vec![prev_val, val]);
                     }
                     Value::Multiple(vec) => vec.push(val),
-                })
-                .or_insert(Value::Single(val));
+                },
+                None => {
+                    data.insert(key, Value::Single(val));
+                }
+            }
         }
 
         QueryString { data }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_keys_and_values() {
+        let qs =
+            QueryString::from("name=Jane+Doe&city=S%C3%A3o%20Paulo&tag=a&tag=b%26c&empty=&flag");
+
+        assert_eq!(qs.get("name").unwrap().first(), "Jane Doe");
+        assert_eq!(qs.get("city").unwrap().first(), "São Paulo");
+        assert_eq!(qs.get("tag").unwrap().all(), vec!["a", "b&c"]);
+        assert_eq!(qs.get("empty").unwrap().first(), "");
+        assert_eq!(qs.get("flag").unwrap().first(), "");
+        assert!(qs.get("missing").is_none());
+    }
+
+    #[test]
+    fn parses_typed_values() {
+        let qs = QueryString::from("page=3&size=big");
+
+        assert_eq!(qs.get_parsed::<u32>("page").unwrap().unwrap(), 3);
+        assert!(qs.get_parsed::<u32>("size").unwrap().is_err());
+        assert!(qs.get_parsed::<u32>("offset").is_none());
+    }
+}
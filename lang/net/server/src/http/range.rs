@@ -0,0 +1,118 @@
+// bytes=0-499, 1000-, -200
+//
+// Byte ranges from a Range header (RFC 7233 §2.1), resolved against a
+// representation of `len` bytes into inclusive (first, last) offsets.
+
+// More ranges than this in one request is far more likely abuse than a
+// media player seeking, so the header is ignored and the whole file sent.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeError {
+    /// The header is malformed or not in bytes; it must be ignored.
+    Invalid,
+    /// None of the ranges overlap the representation; answer 416.
+    Unsatisfiable,
+}
+
+pub fn parse_range(header: &str, len: u64) -> Result<Vec<(u64, u64)>, RangeError> {
+    let header = header.trim();
+    let specs = match header.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => &header[6..],
+        _ => return Err(RangeError::Invalid),
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let i = spec.find('-').ok_or(RangeError::Invalid)?;
+        let (first, last) = (spec[..i].trim(), spec[i + 1..].trim());
+
+        let range = match (first.is_empty(), last.is_empty()) {
+            // -500: the final 500 bytes
+            (true, false) => {
+                let suffix = parse_offset(last)?;
+                if suffix == 0 || len == 0 {
+                    None
+                } else {
+                    Some((len.saturating_sub(suffix), len - 1))
+                }
+            }
+            // 9500-: from 9500 to the end
+            (false, true) => {
+                let first = parse_offset(first)?;
+                if first < len {
+                    Some((first, len - 1))
+                } else {
+                    None
+                }
+            }
+            // 0-499
+            (false, false) => {
+                let (first, last) = (parse_offset(first)?, parse_offset(last)?);
+                if last < first {
+                    return Err(RangeError::Invalid);
+                }
+                if first < len {
+                    Some((first, last.min(len - 1)))
+                } else {
+                    None
+                }
+            }
+            (true, true) => return Err(RangeError::Invalid),
+        };
+
+        // Unsatisfiable ranges are dropped; the rest are still served.
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+        if ranges.len() > MAX_RANGES {
+            return Err(RangeError::Invalid);
+        }
+    }
+
+    if ranges.is_empty() {
+        Err(RangeError::Unsatisfiable)
+    } else {
+        Ok(ranges)
+    }
+}
+
+fn parse_offset(s: &str) -> Result<u64, RangeError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(RangeError::Invalid);
+    }
+    s.parse().map_err(|_| RangeError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_ranges() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Ok(vec![(0, 499)]));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(vec![(900, 999)]));
+        assert_eq!(parse_range("bytes=-200", 1000), Ok(vec![(800, 999)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ok(vec![(0, 999)]));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(vec![(990, 999)]));
+        assert_eq!(
+            parse_range("bytes=0-0, 5-9, 2000-", 1000),
+            Ok(vec![(0, 0), (5, 9)])
+        );
+    }
+
+    #[test]
+    fn reports_invalid_and_unsatisfiable() {
+        assert_eq!(parse_range("items=0-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=5-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=a-b", 1000), Err(RangeError::Invalid));
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=-0", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+    }
+}
@@ -1,19 +1,33 @@
 use super::method::{Method, MethodError};
-use super::QueryString;
+use super::percent::percent_decode;
+use super::{Headers, QueryString, StatusCode};
+use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::net::SocketAddr;
 use std::str;
 use std::str::Utf8Error;
 
 #[derive(Debug)]
 pub struct Request<'buf> {
-    path: &'buf str,
+    target: &'buf str,
+    path: Cow<'buf, str>,
     query_string: Option<QueryString<'buf>>,
     method: Method,
+    headers: Headers<'buf>,
+    body: Vec<u8>,
+    params: Vec<(String, String)>,
+    remote_addr: Option<SocketAddr>,
 }
 
 impl<'buf> Request<'buf> {
+    /// The request target exactly as sent, e.g. `/search?q=a%20b`.
+    pub fn target(&self) -> &str {
+        self.target
+    }
+
+    /// The percent-decoded path, without the query string.
     pub fn path(&self) -> &str {
         &self.path
     }
@@ -22,21 +36,77 @@ impl<'buf> Request<'buf> {
         &self.method
     }
 
-    pub fn query_string(&self) -> Option<&QueryString> {
+    pub fn query_string(&self) -> Option<&QueryString<'_>> {
         self.query_string.as_ref()
     }
+
+    pub fn headers(&self) -> &Headers<'buf> {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers<'buf> {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    /// The address of the client, when the request came in over a socket.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, addr: SocketAddr) {
+        self.remote_addr = Some(addr);
+    }
+
+    /// A parameter captured from the path by a `Router` pattern.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_param(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.params.push((name.into(), value.into()));
+    }
+
+    /// The fields of an `application/x-www-form-urlencoded` body.
+    /// None when the request has a different content type or the body is not UTF-8.
+    pub fn form(&self) -> Option<QueryString<'_>> {
+        let content_type = self.headers.get("Content-Type")?;
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+
+        str::from_utf8(&self.body).ok().map(QueryString::from)
+    }
 }
 
 impl<'buf> TryFrom<&'buf [u8]> for Request<'buf> {
     type Error = ParseError;
 
     // GET /search?name=abc&sort=1 HTTP/1.1\r\n...HEADERS...
+    //
+    // Only the request line and headers are parsed; the body is read separately
+    // because its length depends on the headers.
     fn try_from(buf: &'buf [u8]) -> Result<Request<'buf>, Self::Error> {
         let request = str::from_utf8(buf)?;
 
-        let (method, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
-        let (mut path, request) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
-        let (protocol, _) = get_next_word(request).ok_or(ParseError::InvalidRequest)?;
+        let (request_line, mut request) =
+            get_next_line(request).ok_or(ParseError::InvalidRequest)?;
+
+        let (method, request_line) =
+            get_next_word(request_line).ok_or(ParseError::InvalidRequest)?;
+        let (mut path, protocol) = get_next_word(request_line).ok_or(ParseError::InvalidRequest)?;
 
         if protocol != "HTTP/1.1" {
             return Err(ParseError::InvalidProtocol);
@@ -44,22 +114,71 @@ impl<'buf> TryFrom<&'buf [u8]> for Request<'buf> {
 
         let method: Method = method.parse()?;
 
+        let target = path;
         let mut query_string = None;
         if let Some(i) = path.find('?') {
             query_string = Some(QueryString::from(&path[i + 1..]));
             path = &path[..i];
         }
 
+        let mut headers = Headers::new();
+        while let Some((line, rest)) = get_next_line(request) {
+            request = rest;
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = parse_header(line)?;
+            headers.append(name, value);
+        }
+
         Ok(Self {
-            path,
+            target,
+            path: percent_decode(path, false),
             query_string,
             method,
+            headers,
+            body: Vec::new(),
+            params: Vec::new(),
+            remote_addr: None,
         })
     }
 }
 
+fn get_next_line(request: &str) -> Option<(&str, &str)> {
+    request
+        .find("\r\n")
+        .map(|i| (&request[..i], &request[i + 2..]))
+}
+
+// Host: example.com\r\n
+pub fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    // Obsolete line folding (a line starting with whitespace) is rejected as RFC 7230 §3.2.4 allows.
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Err(ParseError::InvalidHeaderLine);
+    }
+
+    let i = line.find(':').ok_or(ParseError::InvalidHeaderLine)?;
+    let name = &line[..i];
+    let value = line[i + 1..].trim_matches(|c| c == ' ' || c == '\t');
+
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(ParseError::InvalidHeaderName);
+    }
+
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::InvalidHeaderValue);
+    }
+
+    Ok((name, value))
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
 fn get_next_word(request: &str) -> Option<(&str, &str)> {
-    for (i, c) in request.chars().enumerate() {
+    for (i, c) in request.char_indices() {
         if c == ' ' || c == '\r' {
             return Some((&request[..i], &request[i + 1..]));
         }
@@ -68,11 +187,20 @@ fn get_next_word(request: &str) -> Option<(&str, &str)> {
     None
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum ParseError {
     InvalidRequest,
     InvalidEncoding,
     InvalidProtocol,
     InvalidMethod,
+    InvalidHeaderLine,
+    InvalidHeaderName,
+    InvalidHeaderValue,
+    InvalidContentLength,
+    InvalidChunk,
+    InvalidTransferEncoding,
+    HeadTooLarge,
+    BodyTooLarge,
 }
 
 impl ParseError {
@@ -82,6 +210,23 @@ impl ParseError {
             Self::InvalidEncoding => "Invalid Encoding",
             Self::InvalidProtocol => "Invalid Protocol",
             Self::InvalidMethod => "Invalid Method",
+            Self::InvalidHeaderLine => "Invalid Header Line",
+            Self::InvalidHeaderName => "Invalid Header Name",
+            Self::InvalidHeaderValue => "Invalid Header Value",
+            Self::InvalidContentLength => "Invalid Content-Length",
+            Self::InvalidChunk => "Invalid Chunked Encoding",
+            Self::InvalidTransferEncoding => "Invalid Transfer-Encoding",
+            Self::HeadTooLarge => "Request Head Too Large",
+            Self::BodyTooLarge => "Request Body Too Large",
+        }
+    }
+
+    /// The status to answer a request that failed with this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::HeadTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            Self::BodyTooLarge => StatusCode::PayloadTooLarge,
+            _ => StatusCode::BadRequest,
         }
     }
 }
@@ -111,3 +256,47 @@ impl Debug for ParseError {
 }
 
 impl Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let buf = b"GET /search?q=rust HTTP/1.1\r\nHost: localhost:8080\r\nAccept: text/html\r\nAccept:  application/json \r\n\r\n";
+        let request = Request::try_from(&buf[..]).unwrap();
+
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.headers().get("host"), Some("localhost:8080"));
+        let accept: Vec<_> = request.headers().get_all("Accept").collect();
+        assert_eq!(accept, vec!["text/html", "application/json"]);
+    }
+
+    #[test]
+    fn decodes_path_and_form_body() {
+        let buf = b"POST /docs/read%20me.txt?q=a+b HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8\r\n\r\n";
+        let mut request = Request::try_from(&buf[..]).unwrap();
+        request.set_body(b"user=jane%40example.com&n=2".to_vec());
+
+        assert_eq!(request.path(), "/docs/read me.txt");
+        let query_string = request.query_string().unwrap();
+        assert_eq!(query_string.get("q").unwrap().first(), "a b");
+        let form = request.form().unwrap();
+        assert_eq!(form.get("user").unwrap().first(), "jane@example.com");
+        assert_eq!(form.get_parsed::<u8>("n").unwrap().unwrap(), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let cases: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: local\x01host\r\n\r\n",
+        ];
+
+        for buf in cases.iter() {
+            assert!(Request::try_from(*buf).is_err());
+        }
+    }
+}
@@ -1,30 +1,342 @@
-use std::io::{Result as IoResult, Write};
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::{self, Read, Result as IoResult, Write};
+use std::mem;
+use std::time::{Duration, SystemTime};
 
-use super::StatusCode;
+use super::chunked::write_chunked;
+use super::date::format_http_date;
+use super::{Headers, StatusCode};
+
+const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Debug)]
 pub struct Response {
     status_code: StatusCode,
-    body: Option<String>,
+    headers: Headers<'static>,
+    body: Body,
+    upgrade: Option<Upgrade>,
+}
+
+// A body is either held in memory, or read from a source while it is being
+// sent, so large files never have to fit in memory. A source whose length
+// is not known up front is sent chunked. An omitted body only has a length:
+// it answers a HEAD request for something the handler never fetched, like a
+// proxied resource.
+pub enum Body {
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>, u64),
+    Chunked(Box<dyn Read + Send>),
+    Omitted(u64),
+}
+
+impl Body {
+    /// The length of the body, unless it is only known once it is sent.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes.len() as u64),
+            Self::Stream(_, len) | Self::Omitted(len) => Some(*len),
+            Self::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// The body's contents, unless it is streamed or omitted.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            Self::Stream(..) | Self::Chunked(_) | Self::Omitted(_) => None,
+        }
+    }
+}
+
+// The connection as a handler that upgraded it to another protocol sees it.
+// Reads first return whatever the client sent after the upgrade request.
+pub trait Socket: Read + Write {
+    /// Bound each read; one that runs out fails with WouldBlock or TimedOut.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()>;
+
+    /// Whether the server is shutting down and the connection should end.
+    fn is_closing(&self) -> bool;
+}
+
+// Takes over the connection once a 101 Switching Protocols response has
+// been sent. The connection is closed when it returns.
+pub struct Upgrade(Box<OnUpgrade>);
+
+type OnUpgrade = dyn FnOnce(&mut dyn Socket) + Send;
+
+impl Upgrade {
+    pub fn run(self, socket: &mut dyn Socket) {
+        (self.0)(socket)
+    }
+}
+
+impl Debug for Upgrade {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Upgrade")
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Self::Stream(_, len) => write!(f, "Stream({} bytes)", len),
+            Self::Chunked(_) => write!(f, "Chunked"),
+            Self::Omitted(len) => write!(f, "Omitted({} bytes)", len),
+        }
+    }
 }
 
 impl Response {
     pub fn new(status_code: StatusCode, body: Option<String>) -> Self {
-        Response { status_code, body }
+        Response {
+            status_code,
+            headers: Headers::new(),
+            body: Body::Bytes(body.map(String::into_bytes).unwrap_or_default()),
+            upgrade: None,
+        }
+    }
+
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder::new()
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn headers(&self) -> &Headers<'static> {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers<'static> {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn set_body(&mut self, body: impl Into<Vec<u8>>) {
+        self.body = Body::Bytes(body.into());
+    }
+
+    /// Take the body out of the response, leaving an empty one behind.
+    pub fn take_body(&mut self) -> Body {
+        mem::replace(&mut self.body, Body::Bytes(Vec::new()))
+    }
+
+    /// Whether the status allows a body at all. 1xx, 204 and 304 responses
+    /// never have one (RFC 7230 §3.3.3).
+    pub fn allows_body(&self) -> bool {
+        !(self.status_code.is_informational()
+            || self.status_code == StatusCode::NoContent
+            || self.status_code == StatusCode::NotModified)
     }
 
-    pub fn send(&self, stream: &mut impl Write) -> IoResult<()> {
-        let body = match &self.body {
-            Some(b) => b,
-            None => "",
-        };
+    /// Take the protocol upgrade out of the response, if it has one.
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    // Content-Length, or chunking when the length is not known, always comes
+    // from the body, so the client can find the end of it without the
+    // connection being closed. Date and Server are
+    // filled in unless the handler already set them.
+    pub fn send(&mut self, stream: &mut impl Write) -> IoResult<()> {
+        self.write(stream, true)
+    }
+
+    /// Send the status line and headers only, as the answer to a HEAD request.
+    /// Content-Length still describes the body a GET would have received.
+    pub fn send_head(&mut self, stream: &mut impl Write) -> IoResult<()> {
+        self.write(stream, false)
+    }
 
+    fn write(&mut self, stream: &mut impl Write, include_body: bool) -> IoResult<()> {
         write!(
             stream,
-            "HTTP/1.1 {} {}\r\n\r\n{}",
+            "HTTP/1.1 {} {}\r\n",
             self.status_code,
-            self.status_code.reason_phrase(),
-            body
-        )
+            self.status_code.reason_phrase()
+        )?;
+
+        if !self.headers.contains("Date") {
+            write!(stream, "Date: {}\r\n", format_http_date(SystemTime::now()))?;
+        }
+        if !self.headers.contains("Server") {
+            write!(stream, "Server: {}\r\n", SERVER_NAME)?;
+        }
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                write!(stream, "{}: {}\r\n", name, value)?;
+            }
+        }
+
+        if !self.allows_body() {
+            write!(stream, "\r\n")?;
+            return stream.flush();
+        }
+
+        match self.body.len() {
+            Some(len) => write!(stream, "Content-Length: {}\r\n\r\n", len)?,
+            None => write!(stream, "Transfer-Encoding: chunked\r\n\r\n")?,
+        }
+        if include_body {
+            match &mut self.body {
+                Body::Bytes(bytes) => stream.write_all(bytes)?,
+                Body::Stream(reader, len) => {
+                    let copied = io::copy(&mut reader.take(*len), stream)?;
+                    // The length was already promised in Content-Length.
+                    if copied < *len {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "streamed body ended early",
+                        ));
+                    }
+                }
+                Body::Chunked(reader) => write_chunked(reader, stream)?,
+                Body::Omitted(len) if *len > 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "omitted body can only answer HEAD",
+                    ))
+                }
+                Body::Omitted(_) => {}
+            }
+        }
+        stream.flush()
+    }
+}
+
+// Response::builder()
+//     .status(StatusCode::Ok)
+//     .header("Content-Type", "text/plain")
+//     .body("hello")
+#[derive(Debug)]
+pub struct ResponseBuilder {
+    status_code: StatusCode,
+    headers: Headers<'static>,
+}
+
+impl ResponseBuilder {
+    pub fn new() -> Self {
+        Self {
+            status_code: StatusCode::Ok,
+            headers: Headers::new(),
+        }
+    }
+
+    pub fn status(mut self, status_code: StatusCode) -> Self {
+        self.status_code = status_code;
+        self
+    }
+
+    /// Add a header. Calling this twice with the same name sends both values.
+    pub fn header(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn body(self, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status_code: self.status_code,
+            headers: self.headers,
+            body: Body::Bytes(body.into()),
+            upgrade: None,
+        }
+    }
+
+    /// Finish with a body read from `reader` as it is sent.
+    /// Exactly `len` bytes are sent, which `reader` must be able to provide.
+    pub fn stream(self, reader: impl Read + Send + 'static, len: u64) -> Response {
+        Response {
+            status_code: self.status_code,
+            headers: self.headers,
+            body: Body::Stream(Box::new(reader), len),
+            upgrade: None,
+        }
+    }
+
+    /// Finish with a body read from `reader` until it runs out, sent chunked
+    /// as its length is not known up front.
+    pub fn chunked(self, reader: impl Read + Send + 'static) -> Response {
+        Response {
+            status_code: self.status_code,
+            headers: self.headers,
+            body: Body::Chunked(Box::new(reader)),
+            upgrade: None,
+        }
+    }
+
+    /// Finish the answer to a HEAD request, declaring the `len` bytes a GET
+    /// would have received without having them at hand.
+    pub fn head(self, len: u64) -> Response {
+        Response {
+            status_code: self.status_code,
+            headers: self.headers,
+            body: Body::Omitted(len),
+            upgrade: None,
+        }
+    }
+
+    /// Finish a response that has no body.
+    pub fn empty(self) -> Response {
+        self.body(Vec::new())
+    }
+
+    /// Finish a 101 Switching Protocols response, after which `on_upgrade`
+    /// is handed the connection to speak the new protocol on.
+    pub fn upgrade(self, on_upgrade: impl FnOnce(&mut dyn Socket) + Send + 'static) -> Response {
+        Response {
+            status_code: StatusCode::SwitchingProtocols,
+            headers: self.headers,
+            body: Body::Bytes(Vec::new()),
+            upgrade: Some(Upgrade(Box::new(on_upgrade))),
+        }
+    }
+}
+
+impl Default for ResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_headers_and_binary_body() {
+        let mut response = Response::builder()
+            .status(StatusCode::NotFound)
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", "999")
+            .body(vec![0u8, 159, 146, 150]);
+
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+
+        let head_end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&out[..head_end]);
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(head.contains("Content-Length: 4\r\n"));
+        assert!(!head.contains("999"));
+        assert!(head.contains("Date: "));
+        assert!(head.contains("Server: server/"));
+        assert_eq!(&out[head_end..], &[0u8, 159, 146, 150]);
     }
 }
@@ -1,19 +1,134 @@
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-#[derive(Copy, Clone, Debug)]
-pub enum StatusCode {
-    Ok = 200,
-    BadRequest = 400,
-    NotFound = 404,
+// Generates the enum, its reason phrases and the u16 conversion from a single
+// table so the three can never drift apart.
+macro_rules! status_codes {
+    ($($name:ident = $code:literal => $phrase:literal,)+) => {
+        // The IANA HTTP Status Code Registry, RFC 9110 and friends.
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        pub enum StatusCode {
+            $($name = $code,)+
+        }
+
+        impl StatusCode {
+            pub fn reason_phrase(&self) -> &str {
+                match self {
+                    $(Self::$name => $phrase,)+
+                }
+            }
+        }
+
+        impl TryFrom<u16> for StatusCode {
+            type Error = StatusCodeError;
+
+            fn try_from(code: u16) -> Result<Self, Self::Error> {
+                match code {
+                    $($code => Ok(Self::$name),)+
+                    _ => Err(StatusCodeError),
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Continue = 100 => "Continue",
+    SwitchingProtocols = 101 => "Switching Protocols",
+    Processing = 102 => "Processing",
+    EarlyHints = 103 => "Early Hints",
+
+    Ok = 200 => "OK",
+    Created = 201 => "Created",
+    Accepted = 202 => "Accepted",
+    NonAuthoritativeInformation = 203 => "Non-Authoritative Information",
+    NoContent = 204 => "No Content",
+    ResetContent = 205 => "Reset Content",
+    PartialContent = 206 => "Partial Content",
+    MultiStatus = 207 => "Multi-Status",
+    AlreadyReported = 208 => "Already Reported",
+    ImUsed = 226 => "IM Used",
+
+    MultipleChoices = 300 => "Multiple Choices",
+    MovedPermanently = 301 => "Moved Permanently",
+    Found = 302 => "Found",
+    SeeOther = 303 => "See Other",
+    NotModified = 304 => "Not Modified",
+    UseProxy = 305 => "Use Proxy",
+    TemporaryRedirect = 307 => "Temporary Redirect",
+    PermanentRedirect = 308 => "Permanent Redirect",
+
+    BadRequest = 400 => "Bad Request",
+    Unauthorized = 401 => "Unauthorized",
+    PaymentRequired = 402 => "Payment Required",
+    Forbidden = 403 => "Forbidden",
+    NotFound = 404 => "Not Found",
+    MethodNotAllowed = 405 => "Method Not Allowed",
+    NotAcceptable = 406 => "Not Acceptable",
+    ProxyAuthenticationRequired = 407 => "Proxy Authentication Required",
+    RequestTimeout = 408 => "Request Timeout",
+    Conflict = 409 => "Conflict",
+    Gone = 410 => "Gone",
+    LengthRequired = 411 => "Length Required",
+    PreconditionFailed = 412 => "Precondition Failed",
+    PayloadTooLarge = 413 => "Payload Too Large",
+    UriTooLong = 414 => "URI Too Long",
+    UnsupportedMediaType = 415 => "Unsupported Media Type",
+    RangeNotSatisfiable = 416 => "Range Not Satisfiable",
+    ExpectationFailed = 417 => "Expectation Failed",
+    ImATeapot = 418 => "I'm a teapot",
+    MisdirectedRequest = 421 => "Misdirected Request",
+    UnprocessableEntity = 422 => "Unprocessable Entity",
+    Locked = 423 => "Locked",
+    FailedDependency = 424 => "Failed Dependency",
+    TooEarly = 425 => "Too Early",
+    UpgradeRequired = 426 => "Upgrade Required",
+    PreconditionRequired = 428 => "Precondition Required",
+    TooManyRequests = 429 => "Too Many Requests",
+    RequestHeaderFieldsTooLarge = 431 => "Request Header Fields Too Large",
+    UnavailableForLegalReasons = 451 => "Unavailable For Legal Reasons",
+
+    InternalServerError = 500 => "Internal Server Error",
+    NotImplemented = 501 => "Not Implemented",
+    BadGateway = 502 => "Bad Gateway",
+    ServiceUnavailable = 503 => "Service Unavailable",
+    GatewayTimeout = 504 => "Gateway Timeout",
+    HttpVersionNotSupported = 505 => "HTTP Version Not Supported",
+    VariantAlsoNegotiates = 506 => "Variant Also Negotiates",
+    InsufficientStorage = 507 => "Insufficient Storage",
+    LoopDetected = 508 => "Loop Detected",
+    NotExtended = 510 => "Not Extended",
+    NetworkAuthenticationRequired = 511 => "Network Authentication Required",
 }
 
 impl StatusCode {
-    pub fn reason_phrase(&self) -> &str {
-        match self {
-            Self::Ok => "Ok",
-            Self::BadRequest => "Bad Request",
-            Self::NotFound => "Not Found",
-        }
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+
+    /// 1xx
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    /// 2xx
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    /// 3xx
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    /// 4xx
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    /// 5xx
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.as_u16())
     }
 }
 
@@ -22,3 +137,32 @@ impl Display for StatusCode {
         write!(f, "{}", *self as u16)
     }
 }
+
+#[derive(Debug)]
+pub struct StatusCodeError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_from_u16() {
+        assert_eq!(
+            StatusCode::try_from(405).unwrap(),
+            StatusCode::MethodNotAllowed
+        );
+        assert_eq!(StatusCode::try_from(200).unwrap().reason_phrase(), "OK");
+        assert!(StatusCode::try_from(299).is_err());
+        assert!(StatusCode::try_from(42).is_err());
+    }
+
+    #[test]
+    fn classifies() {
+        assert!(StatusCode::SwitchingProtocols.is_informational());
+        assert!(StatusCode::NoContent.is_success());
+        assert!(StatusCode::NotModified.is_redirection());
+        assert!(StatusCode::PayloadTooLarge.is_client_error());
+        assert!(StatusCode::ServiceUnavailable.is_server_error());
+        assert!(!StatusCode::NotFound.is_success());
+    }
+}
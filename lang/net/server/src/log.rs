@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// How much the server prints. Each level includes the ones above it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = LevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(LevelError),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct LevelError;
+
+impl Display for LevelError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "expected one of error, warn, info or debug")
+    }
+}
+
+// error!("Failed to send response: {}", e);
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!(Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!(Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!(Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!(Debug, $($arg)*) };
+}
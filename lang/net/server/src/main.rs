@@ -1,17 +1,173 @@
 #![allow(dead_code)]
 
+use cgi::CgiHandler;
+use config::{Args, Config, Settings, USAGE};
+use error_pages::ErrorPages;
+use middleware::{AccessLog, Auth, Chain, Compression, Credentials, RequestId, Timing};
+use proxy::ProxyHandler;
 use server::Server;
 use std::env;
+use std::path::PathBuf;
+use std::process;
+use tls::TlsConfig;
 use website_handler::WebsiteHandler;
 
+#[macro_use]
+mod log;
+
+mod async_server;
+mod autoindex;
+mod cgi;
+mod config;
+#[cfg(test)]
+mod conformance;
+mod connection;
+mod error_pages;
+mod escape;
 mod http;
+mod middleware;
+mod path_resolver;
+mod proxy;
+mod router;
 mod server;
+mod shutdown;
+mod template;
+mod thread_pool;
+mod tls;
 mod website_handler;
+mod websocket;
 
 fn main() {
-    let default_path = format!("{}/public", env!("CARGO_MANIFEST_DIR"));
-    let public_path = env::var("PUBLIC_PATH").unwrap_or(default_path);
-    println!("public path: {}", public_path);
-    let server = Server::new("127.0.0.1:8080".to_string());
-    server.run(WebsiteHandler::new(public_path));
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    if args.help {
+        print!("{}", USAGE);
+        return;
+    }
+
+    let check_config = args.check_config;
+    let config = load_config(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if check_config {
+        println!("configuration OK");
+        println!("  listen: {}", config.listen.join(", "));
+        println!("  public path: {}", config.public_path.display());
+        if let Some((cert, _)) = &config.tls {
+            println!("  TLS certificate: {}", cert.display());
+        }
+        if let Some(dir) = &config.cgi_bin {
+            println!("  CGI scripts: {}", dir.display());
+        }
+        return;
+    }
+    log::set_level(config.log_level);
+    info!("public path: {}", config.public_path.display());
+
+    let mut server = Server::new(config.listen[0].clone())
+        .keep_alive_timeout(config.keep_alive_timeout)
+        .max_body_size(config.max_body_size)
+        .max_requests_per_connection(config.max_requests_per_connection)
+        .shutdown_timeout(config.shutdown_timeout);
+    for addr in &config.listen[1..] {
+        server = server.bind(addr.clone());
+    }
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    if let Some((cert, key)) = &config.tls {
+        let tls = TlsConfig::from_pem_files(cert, key).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to load TLS certificate {} and key {}: {}",
+                cert.display(),
+                key.display(),
+                e
+            );
+            process::exit(1);
+        });
+        server = server.tls(tls);
+        if let Some(redirect_addr) = &config.redirect_http {
+            server = server.redirect_http(redirect_addr.clone());
+        }
+    }
+
+    let public_path = config.public_path.to_string_lossy().into_owned();
+    let mut website = WebsiteHandler::new(public_path.clone())
+        .precompressed(true)
+        .autoindex(config.autoindex)
+        .templates(config.templates);
+    if let Some(dir) = &config.template_data {
+        website = website.template_data(dir);
+    }
+    if let Some(dir) = &config.cgi_bin {
+        website = website.exclude(dir);
+    }
+    let mut website = CgiHandler::new(website).timeout(config.cgi_timeout);
+    if let Some(dir) = &config.cgi_bin {
+        website = website.route("/cgi-bin/", dir);
+    }
+    let mut website = ProxyHandler::new(website).https(config.tls.is_some());
+    for (prefix, upstream) in &config.proxy {
+        website = website.route(prefix, upstream);
+    }
+    let mut handler = Chain::new(website);
+    if log::enabled(log::Level::Info) {
+        handler = handler.with(AccessLog::stdout());
+    }
+    let mut handler = handler.with(RequestId::new()).with(Timing);
+    if !config.auth.is_empty() {
+        handler = handler.with(load_auth(&config));
+    }
+    let handler = handler.with(Compression::new());
+
+    let mut handler = ErrorPages::new(handler, public_path);
+    for (status, path) in &config.error_pages {
+        handler = handler.page(*status, &path.to_string_lossy());
+    }
+
+    if let Err(e) = server.shutdown_handle().on_signals() {
+        error!("Failed to install signal handlers: {}", e);
+    }
+    if let Err(e) = server.run(handler) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    info!("Shut down");
+}
+
+fn load_auth(config: &Config) -> Auth {
+    let mut auth = Auth::new("Restricted");
+    for rule in &config.auth {
+        let mut credentials = Ok(Credentials::new());
+        if let Some(path) = &rule.htpasswd {
+            credentials = credentials.and_then(|c| c.htpasswd(path));
+        }
+        if let Some(path) = &rule.tokens {
+            credentials = credentials.and_then(|c| c.tokens(path));
+        }
+        let credentials = credentials.unwrap_or_else(|e| {
+            eprintln!("Failed to load credentials for {}: {}", rule.prefix, e);
+            process::exit(1);
+        });
+        auth = auth.protect(&rule.prefix, credentials);
+    }
+    auth
+}
+
+// Defaults, then the config file, then the environment, then the command
+// line, each overriding what came before.
+fn load_config(args: Args) -> Result<Config, config::ConfigError> {
+    let mut settings = Settings::default();
+    if let Some(path) = &args.config {
+        settings = settings.merge(Settings::from_file(path)?);
+    }
+    settings = settings
+        .merge(Settings::from_env(|name| env::var(name).ok()))
+        .merge(args.settings);
+
+    let default_public_path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/public"));
+    Config::from_settings(settings, default_public_path)
 }
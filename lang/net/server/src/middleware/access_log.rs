@@ -0,0 +1,85 @@
+use super::{Middleware, Next};
+use crate::http::date::format_clf_date;
+use crate::http::{Request, Response};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// One line per request in the Common Log Format:
+//
+//   127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let received = SystemTime::now();
+        let response = next.run(request);
+
+        let line = common_log_line(request, &response, received);
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(out, "{}", line) {
+            error!("Failed to write access log: {}", e);
+        }
+
+        response
+    }
+}
+
+fn common_log_line(request: &Request, response: &Response, received: SystemTime) -> String {
+    let host = request
+        .remote_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let bytes = match response.body().len() {
+        Some(0) | None => "-".to_string(),
+        Some(len) => len.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{} {} HTTP/1.1\" {} {}",
+        host,
+        format_clf_date(received),
+        request.method().as_str(),
+        request.target(),
+        response.status_code(),
+        bytes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+    use std::convert::TryFrom;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn formats_common_log_line() {
+        let mut request =
+            Request::try_from(&b"GET /apache_pb.gif?x=1 HTTP/1.1\r\n\r\n"[..]).unwrap();
+        request.set_remote_addr("127.0.0.1:51234".parse().unwrap());
+        let response = Response::builder()
+            .status(StatusCode::Ok)
+            .body(vec![0; 2326]);
+        let received = UNIX_EPOCH + Duration::from_secs(971_186_136);
+
+        assert_eq!(
+            common_log_line(&request, &response, received),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?x=1 HTTP/1.1\" 200 2326"
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use super::Middleware;
+use crate::http::{Request, Response, StatusCode};
+use crate::path_resolver::{has_path_prefix, normalize_path};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+// Who may access a protected path: users from an htpasswd file, who log in
+// with HTTP Basic, and bearer tokens.
+//
+//   # users.htpasswd, from `htpasswd -B` or an argon2 tool
+//   ann:$2y$10$...
+//   bob:$argon2id$v=19$m=19456,t=2,p=1$...
+//
+//   # tokens.txt, one per line
+//   3f9a0c...
+#[derive(Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+    // Tokens are compared by digest, which makes every comparison take the
+    // same time whatever the lengths involved.
+    tokens: Vec<[u8; 20]>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the users in an htpasswd file. Only bcrypt and argon2 hashes are
+    /// accepted.
+    pub fn htpasswd(mut self, path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        for (i, line) in entries(&text) {
+            let invalid = |reason| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), i + 1, reason),
+                )
+            };
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected user:hash"))?;
+            if !is_supported_hash(hash) {
+                return Err(invalid("not a bcrypt or argon2 hash"));
+            }
+            self.users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(self)
+    }
+
+    /// Add the tokens in a file, one per line.
+    pub fn tokens(mut self, path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        self.tokens
+            .extend(entries(&text).map(|(_, token)| digest(token)));
+        Ok(self)
+    }
+
+    fn accepts_basic(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    fn accepts_bearer(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    // The value of an Authorization header (RFC 7617, RFC 6750).
+    fn verify(&self, authorization: &str) -> bool {
+        let (scheme, value) = authorization
+            .trim()
+            .split_once(' ')
+            .unwrap_or((authorization, ""));
+        if scheme.eq_ignore_ascii_case("Bearer") && self.accepts_bearer() {
+            let presented = digest(value.trim());
+            // No early exit, so the time taken gives away nothing about
+            // which token came close.
+            let matched = self.tokens.iter().fold(0u8, |matched, token| {
+                matched | token.ct_eq(&presented).unwrap_u8()
+            });
+            return matched == 1;
+        }
+        if scheme.eq_ignore_ascii_case("Basic") && self.accepts_basic() {
+            let decoded = match BASE64.decode(value.trim()) {
+                Ok(decoded) => decoded,
+                Err(_) => return false,
+            };
+            let decoded = String::from_utf8_lossy(&decoded);
+            let (user, password) = match decoded.split_once(':') {
+                Some(pair) => pair,
+                None => return false,
+            };
+            // Unknown users are checked against some other hash, so they take
+            // as long to turn away as a wrong password does.
+            let hash = self.users.get(user);
+            let checked = hash.or_else(|| self.users.values().next());
+            let verified = checked.is_some_and(|checked| verify_hash(password, checked));
+            return verified && hash.is_some();
+        }
+        false
+    }
+}
+
+// Lines that are not blank or # comments, with their index.
+fn entries(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn digest(token: &str) -> [u8; 20] {
+    Sha1::digest(token.as_bytes()).into()
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$", "$argon2"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    } else {
+        pwhash::bcrypt::verify(password, hash)
+    }
+}
+
+// Protects path prefixes with Credentials. A request without valid ones is
+// answered with 401 Unauthorized and a WWW-Authenticate challenge for each
+// scheme the prefix accepts:
+//
+//   Auth::new("Reports")
+//       .protect("/reports/", Credentials::new().htpasswd(path)?)
+//
+// Prefixes match whole segments of the normalized path, so "/reports" also
+// covers "//reports/q1.html" and "/./reports", but not "/reportsX". Paths
+// that cannot be normalized are refused with 400 Bad Request.
+//
+// A client address that fails too many times in a row is locked out, and
+// answered with 429 Too Many Requests until the lockout has passed since
+// its last failure. Requests that carry no credentials at all do not count,
+// since browsers send one of those before asking for a password.
+pub struct Auth {
+    realm: String,
+    rules: Vec<(String, Credentials)>,
+    max_failures: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Auth {
+    pub fn new(realm: &str) -> Self {
+        Self {
+            realm: realm.replace('"', ""),
+            rules: Vec::new(),
+            max_failures: DEFAULT_MAX_FAILURES,
+            lockout: DEFAULT_LOCKOUT,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Require `credentials` for `prefix` and the paths below it. When
+    /// prefixes overlap, the longest one wins.
+    pub fn protect(mut self, prefix: &str, credentials: Credentials) -> Self {
+        self.rules.push((prefix.to_string(), credentials));
+        self
+    }
+
+    /// Lock an address out for `duration` after `max_failures` failed
+    /// attempts.
+    pub fn lockout(mut self, max_failures: u32, duration: Duration) -> Self {
+        self.max_failures = max_failures;
+        self.lockout = duration;
+        self
+    }
+
+    fn credentials_for(&self, path: &str) -> Option<&Credentials> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| has_path_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, credentials)| credentials)
+    }
+
+    // How much longer `ip` is locked out for, if it is.
+    fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let entry = failures.get(&ip)?;
+        let elapsed = entry.last.elapsed();
+        if entry.count >= self.max_failures && elapsed < self.lockout {
+            Some(self.lockout - elapsed)
+        } else {
+            None
+        }
+    }
+
+    fn record_failure(&self, ip: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        // Forget addresses whose failures have all expired.
+        let lockout = self.lockout;
+        failures.retain(|_, entry| entry.last.elapsed() < lockout);
+
+        let entry = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        entry.count += 1;
+        entry.last = Instant::now();
+        if entry.count == self.max_failures {
+            warn!("Locked out {} after {} failed logins", ip, entry.count);
+        }
+    }
+
+    fn challenge(&self, credentials: &Credentials, rejected: bool) -> Response {
+        let mut response = Response::builder().status(StatusCode::Unauthorized);
+        if credentials.accepts_basic() {
+            response = response.header(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            );
+        }
+        if credentials.accepts_bearer() {
+            let error = if rejected {
+                ", error=\"invalid_token\""
+            } else {
+                ""
+            };
+            response = response.header(
+                "WWW-Authenticate",
+                format!("Bearer realm=\"{}\"{}", self.realm, error),
+            );
+        }
+        response.empty()
+    }
+}
+
+impl Middleware for Auth {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let path = match normalize_path(request.path()) {
+            Some(path) => path,
+            None => return Some(Response::new(StatusCode::BadRequest, None)),
+        };
+        let credentials = self.credentials_for(&path)?;
+        let ip = request.remote_addr().map(|addr| addr.ip());
+
+        if let Some(remaining) = ip.and_then(|ip| self.locked_out(ip)) {
+            return Some(
+                Response::builder()
+                    .status(StatusCode::TooManyRequests)
+                    .header("Retry-After", (remaining.as_secs() + 1).to_string())
+                    .empty(),
+            );
+        }
+
+        let authorization = match request.headers().get("Authorization") {
+            Some(authorization) => authorization,
+            None => return Some(self.challenge(credentials, false)),
+        };
+        if credentials.verify(authorization) {
+            if let Some(ip) = ip {
+                self.failures.lock().unwrap().remove(&ip);
+            }
+            return None;
+        }
+
+        if let Some(ip) = ip {
+            self.record_failure(ip);
+        }
+        warn!(
+            "Failed authentication for {} from {}",
+            request.path(),
+            ip.map_or_else(|| "unknown address".to_string(), |ip| ip.to_string())
+        );
+        Some(self.challenge(credentials, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::server::Handler;
+    use pwhash::bcrypt::{self, BcryptSetup};
+    use std::convert::TryFrom;
+    use std::env;
+
+    fn credentials() -> Credentials {
+        let dir = env::temp_dir().join(format!("auth_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bcrypt_hash = bcrypt::hash_with(
+            BcryptSetup {
+                cost: Some(4),
+                ..Default::default()
+            },
+            "secret",
+        )
+        .unwrap();
+        let argon2_config = argon2::Config {
+            mem_cost: 64,
+            time_cost: 1,
+            ..Default::default()
+        };
+        let argon2_hash = argon2::hash_encoded(b"hunter2", b"somesalt", &argon2_config).unwrap();
+        fs::write(
+            dir.join("htpasswd"),
+            format!("# users\nann:{}\nbob:{}\n", bcrypt_hash, argon2_hash),
+        )
+        .unwrap();
+        fs::write(dir.join("tokens"), "token-one\n\ntoken-two\n").unwrap();
+
+        fs::write(dir.join("plain"), "eve:password\n").unwrap();
+        assert!(Credentials::new().htpasswd(&dir.join("plain")).is_err());
+
+        Credentials::new()
+            .htpasswd(&dir.join("htpasswd"))
+            .unwrap()
+            .tokens(&dir.join("tokens"))
+            .unwrap()
+    }
+
+    fn get(handler: &impl Handler, path: &str, authorization: Option<&str>) -> Response {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(authorization) = authorization {
+            raw.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        raw.push_str("\r\n");
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        request.set_remote_addr("192.0.2.7:4000".parse().unwrap());
+        handler.handle_request(&mut request)
+    }
+
+    fn ok(_: &mut Request) -> Response {
+        Response::builder().body("ok")
+    }
+
+    #[test]
+    fn accepts_basic_and_bearer_credentials() {
+        let handler = Chain::new(ok).with(Auth::new("Reports").protect("/private/", credentials()));
+
+        let response = get(&handler, "/private/a", None);
+        assert_eq!(response.status_code(), StatusCode::Unauthorized);
+        let challenges: Vec<&str> = response.headers().get_all("WWW-Authenticate").collect();
+        assert_eq!(
+            challenges,
+            vec![
+                "Basic realm=\"Reports\", charset=\"UTF-8\"",
+                "Bearer realm=\"Reports\""
+            ]
+        );
+
+        let basic = |user_password: &str| format!("Basic {}", BASE64.encode(user_password));
+        for authorization in [
+            basic("ann:secret"),
+            basic("bob:hunter2"),
+            "Bearer token-two".to_string(),
+            "bearer  token-one".to_string(),
+        ] {
+            let response = get(&handler, "/private/a", Some(&authorization));
+            assert_eq!(response.status_code(), StatusCode::Ok, "{}", authorization);
+        }
+        for authorization in [
+            basic("ann:hunter2"),
+            basic("nobody:secret"),
+            "Bearer token".to_string(),
+            "Digest x".to_string(),
+        ] {
+            let response = get(&handler, "/private/a", Some(&authorization));
+            assert_eq!(response.status_code(), StatusCode::Unauthorized);
+        }
+
+        assert_eq!(get(&handler, "/public", None).status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn locks_out_repeated_failures() {
+        let auth = Auth::new("Reports")
+            .protect("/", credentials())
+            .lockout(2, Duration::from_millis(300));
+        let handler = Chain::new(ok).with(auth);
+
+        // Failures in a row lock the address out, even for good credentials.
+        assert_eq!(
+            get(&handler, "/", None).status_code(),
+            StatusCode::Unauthorized
+        );
+        for _ in 0..2 {
+            let response = get(&handler, "/", Some("Bearer wrong"));
+            assert_eq!(response.status_code(), StatusCode::Unauthorized);
+            assert_eq!(
+                response.headers().get_all("WWW-Authenticate").nth(1),
+                Some("Bearer realm=\"Reports\", error=\"invalid_token\"")
+            );
+        }
+        let response = get(&handler, "/", Some("Bearer token-one"));
+        assert_eq!(response.status_code(), StatusCode::TooManyRequests);
+        assert_eq!(response.headers().get("Retry-After"), Some("1"));
+
+        std::thread::sleep(Duration::from_millis(350));
+        let response = get(&handler, "/", Some("Bearer token-one"));
+        assert_eq!(response.status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn matches_normalized_segments() {
+        let handler = Chain::new(ok).with(Auth::new("Reports").protect("/reports", credentials()));
+
+        for path in [
+            "/reports",
+            "/reports/secret.html",
+            "//reports/secret.html",
+            "/./reports/secret.html",
+            "/%2Freports/secret.html",
+            "/%2E/reports/secret.html",
+        ]
+        .iter()
+        {
+            let response = get(&handler, path, None);
+            assert_eq!(response.status_code(), StatusCode::Unauthorized, "{}", path);
+        }
+        let response = get(&handler, "/public/../reports/secret.html", None);
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+        assert_eq!(
+            get(&handler, "/reportsX", None).status_code(),
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn prefers_the_longest_prefix() {
+        let dir = env::temp_dir().join(format!("auth_overlap_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("public"), "public-token\n").unwrap();
+        fs::write(dir.join("admin"), "admin-token\n").unwrap();
+        let auth = Auth::new("Site")
+            .protect("/", Credentials::new().tokens(&dir.join("public")).unwrap())
+            .protect(
+                "/admin",
+                Credentials::new().tokens(&dir.join("admin")).unwrap(),
+            );
+        let handler = Chain::new(ok).with(auth);
+
+        let public = Some("Bearer public-token");
+        let admin = Some("Bearer admin-token");
+        assert_eq!(get(&handler, "/", public).status_code(), StatusCode::Ok);
+        assert_eq!(
+            get(&handler, "/admin/users", public).status_code(),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            get(&handler, "/admin/users", admin).status_code(),
+            StatusCode::Ok
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use super::Middleware;
+use crate::http::encoding::{self, Encoding};
+use crate::http::response::Body;
+use crate::http::{Request, Response, StatusCode};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Read, Write};
+
+const DEFAULT_MIN_SIZE: u64 = 1024;
+const DEFAULT_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+// Entries ending in '/' match every subtype.
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/manifest+json",
+    "application/wasm",
+    "application/xml",
+    "image/svg+xml",
+];
+
+// Compresses response bodies with the best encoding the client accepts.
+//
+// Only successful responses whose Content-Type is on the allow-list are
+// compressed, and only when the body is worth it: tiny bodies barely shrink
+// and already compressed formats like images do not shrink at all. Streamed
+// bodies have to be read into memory first, as the compressed length must be
+// known up front, so bodies above `max_size`, or of unknown length, are sent
+// as they are.
+pub struct Compression {
+    min_size: u64,
+    max_size: u64,
+    content_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+            content_types: DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+
+    /// Leave bodies smaller than `min_size` bytes uncompressed.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Leave bodies larger than `max_size` bytes uncompressed.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Replace the allow-list of content types. An entry ending in '/', like
+    /// "text/", matches every subtype.
+    pub fn content_types(mut self, content_types: &[&str]) -> Self {
+        self.content_types = content_types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    fn compressible(&self, response: &Response) -> bool {
+        let headers = response.headers();
+        if response.status_code() != StatusCode::Ok
+            || headers.contains("Content-Encoding")
+            || headers.contains_token("Cache-Control", "no-transform")
+        {
+            return false;
+        }
+
+        // An omitted body has no bytes to compress.
+        if let Body::Omitted(_) = response.body() {
+            return false;
+        }
+        let len = match response.body().len() {
+            Some(len) => len,
+            None => return false,
+        };
+        if len < self.min_size || len > self.max_size {
+            return false;
+        }
+
+        let content_type = match headers.get("Content-Type") {
+            Some(content_type) => content_type.split(';').next().unwrap_or("").trim(),
+            None => return false,
+        };
+        self.content_types.iter().any(|allowed| {
+            if allowed.ends_with('/') {
+                content_type.starts_with(allowed.as_str())
+            } else {
+                content_type.eq_ignore_ascii_case(allowed)
+            }
+        })
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: &mut Response) {
+        if !self.compressible(response) {
+            return;
+        }
+
+        // Caches must keep one copy per encoding, whichever this client gets.
+        if !response.headers().contains_token("Vary", "Accept-Encoding") {
+            response.headers_mut().append("Vary", "Accept-Encoding");
+        }
+
+        let encoding = match request
+            .headers()
+            .get("Accept-Encoding")
+            .and_then(|accept| encoding::negotiate(accept, &Encoding::ALL))
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let body = match read_body(response.take_body()) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to read response body: {}", e);
+                *response = Response::new(StatusCode::InternalServerError, None);
+                return;
+            }
+        };
+
+        let compressed = match compress(&body, encoding) {
+            Ok(compressed) if compressed.len() < body.len() => compressed,
+            _ => {
+                response.set_body(body);
+                return;
+            }
+        };
+
+        let headers = response.headers_mut();
+        headers.insert("Content-Encoding", encoding.as_str());
+        // The compressed bytes differ from the original ones, so a strong
+        // validator no longer describes them.
+        if let Some(etag) = headers.get("ETag") {
+            if !etag.starts_with("W/") {
+                let weak = format!("W/{}", etag);
+                headers.insert("ETag", weak);
+            }
+        }
+        response.set_body(compressed);
+    }
+}
+
+fn read_body(body: Body) -> io::Result<Vec<u8>> {
+    match body {
+        Body::Bytes(bytes) => Ok(bytes),
+        Body::Stream(reader, len) => {
+            let mut bytes = Vec::with_capacity(len as usize);
+            reader.take(len).read_to_end(&mut bytes)?;
+            if (bytes.len() as u64) < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "streamed body ended early",
+                ));
+            }
+            Ok(bytes)
+        }
+        Body::Chunked(mut reader) => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+        Body::Omitted(_) => Ok(Vec::new()),
+    }
+}
+
+// "deflate" is the zlib format (RFC 7230 §4.2.2), not raw deflate.
+fn compress(body: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                // Quality 5 compresses about as well as gzip -9, many times faster
+                // than brotli's maximum of 11.
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(body)?;
+            }
+            Ok(out)
+        }
+        Encoding::Gzip => {
+            let mut writer = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            writer.write_all(body)?;
+            writer.finish()
+        }
+        Encoding::Deflate => {
+            let mut writer = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            writer.write_all(body)?;
+            writer.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::server::Handler;
+    use flate2::read::GzDecoder;
+    use std::convert::TryFrom;
+
+    fn call(handler: &impl Handler, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        handler.handle_request(&mut request)
+    }
+
+    fn page(content_type: &'static str, len: usize) -> impl Handler {
+        move |_: &mut Request| {
+            Response::builder()
+                .header("Content-Type", content_type)
+                .header("ETag", "\"abc\"")
+                .body("a".repeat(len))
+        }
+    }
+
+    #[test]
+    fn compresses_with_negotiated_encoding() {
+        let chain = Chain::new(page("text/html; charset=utf-8", 4000)).with(Compression::new());
+
+        let response = call(&chain, "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers().get("ETag"), Some("W/\"abc\""));
+        let mut decoded = String::new();
+        GzDecoder::new(response.body().as_bytes().unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "a".repeat(4000));
+
+        let raw = "GET / HTTP/1.1\r\nAccept-Encoding: gzip;q=0.5, br\r\n\r\n";
+        let response = call(&chain, raw);
+        assert_eq!(response.headers().get("Content-Encoding"), Some("br"));
+
+        let response = call(&chain, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body().len(), Some(4000));
+    }
+
+    #[test]
+    fn skips_small_and_unlisted_bodies() {
+        let raw = "GET / HTTP/1.1\r\nAccept-Encoding: gzip, br\r\n\r\n";
+
+        let chain = Chain::new(page("text/plain", 100)).with(Compression::new());
+        let response = call(&chain, raw);
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.headers().get("ETag"), Some("\"abc\""));
+
+        let chain = Chain::new(page("image/png", 4000)).with(Compression::new());
+        assert_eq!(call(&chain, raw).headers().get("Content-Encoding"), None);
+
+        let chain =
+            Chain::new(page("image/png", 4000)).with(Compression::new().content_types(&["image/"]));
+        assert_eq!(
+            call(&chain, raw).headers().get("Content-Encoding"),
+            Some("br")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+pub use access_log::AccessLog;
+pub use auth::{Auth, Credentials};
+pub use compression::Compression;
+pub use request_id::RequestId;
+pub use timing::Timing;
+
+pub mod access_log;
+pub mod auth;
+pub mod compression;
+pub mod request_id;
+pub mod timing;
+
+use crate::http::{ParseError, Request, Response};
+use crate::server::Handler;
+
+// Code that runs around every request, whatever handler ends up serving it.
+//
+// Most middleware only needs the hooks: `before` may answer the request
+// itself, which skips the handler and every middleware after this one, and
+// `after` may adjust the response on its way out. Middleware that needs to
+// keep state across the call, like a timer, overrides `handle` instead.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let mut response = match self.before(request) {
+            Some(response) => response,
+            None => next.run(request),
+        };
+        self.after(request, &mut response);
+        response
+    }
+
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+// The rest of the chain: the remaining middleware, then the handler.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle_request(request),
+        }
+    }
+}
+
+// A handler wrapped in middleware. The first middleware added is the
+// outermost: it sees the request first and the response last.
+//
+//   Chain::new(WebsiteHandler::new(public_path))
+//       .with(AccessLog::stdout())
+//       .with(RequestId::new())
+//       .with(Timing)
+pub struct Chain<H> {
+    handler: H,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl<H: Handler> Chain<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+impl<H: Handler> Handler for Chain<H> {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: &self.handler,
+        }
+        .run(request)
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.handler.handle_bad_request(e)
+    }
+
+    fn handle_panic(&self) -> Response {
+        self.handler.handle_panic()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    // Records the order hooks run in.
+    struct Trace(&'static str, &'static Mutex<Vec<String>>);
+
+    impl Middleware for Trace {
+        fn before(&self, request: &mut Request) -> Option<Response> {
+            self.1.lock().unwrap().push(format!("before {}", self.0));
+            if request.path() == "/blocked" && self.0 == "outer" {
+                return Some(Response::new(StatusCode::Forbidden, None));
+            }
+            None
+        }
+
+        fn after(&self, _request: &Request, _response: &mut Response) {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+        }
+    }
+
+    #[test]
+    fn runs_hooks_in_order_and_short_circuits() {
+        static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        let chain = Chain::new(|_: &mut Request| {
+            LOG.lock().unwrap().push("handler".to_string());
+            Response::new(StatusCode::Ok, None)
+        })
+        .with(Trace("outer", &LOG))
+        .with(Trace("inner", &LOG));
+
+        let mut request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+        chain.handle_request(&mut request);
+        assert_eq!(
+            *LOG.lock().unwrap(),
+            vec![
+                "before outer",
+                "before inner",
+                "handler",
+                "after inner",
+                "after outer"
+            ]
+        );
+
+        LOG.lock().unwrap().clear();
+        let mut request = Request::try_from(&b"GET /blocked HTTP/1.1\r\n\r\n"[..]).unwrap();
+        let response = chain.handle_request(&mut request);
+        assert_eq!(response.status_code(), StatusCode::Forbidden);
+        assert_eq!(*LOG.lock().unwrap(), vec!["before outer", "after outer"]);
+    }
+}
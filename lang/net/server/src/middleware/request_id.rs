@@ -0,0 +1,88 @@
+use super::Middleware;
+use crate::http::{Request, Response};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "X-Request-Id";
+const MAX_LEN: usize = 128;
+
+// Tags each request with an X-Request-Id header that handlers can read and
+// that is echoed on the response, so a client report can be matched to the
+// server's logs. An ID sent by the client or a proxy in front is kept.
+pub struct RequestId {
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        // The start time keeps IDs from repeating across restarts.
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+
+        Self {
+            prefix: format!("{:x}", started),
+            counter: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let usable = match request.headers().get(HEADER) {
+            Some(id) => !id.is_empty() && id.len() <= MAX_LEN,
+            None => false,
+        };
+
+        if !usable {
+            let n = self.counter.fetch_add(1, Ordering::Relaxed);
+            request
+                .headers_mut()
+                .insert(HEADER, format!("{}-{}", self.prefix, n));
+        }
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(id) = request.headers().get(HEADER) {
+            response.headers_mut().insert(HEADER, id.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::server::Handler;
+    use std::convert::TryFrom;
+
+    fn id_seen_by_handler(request: &mut Request) -> Response {
+        let id = request.headers().get(HEADER).unwrap_or("").to_string();
+        Response::builder().body(id)
+    }
+
+    #[test]
+    fn injects_and_keeps_ids() {
+        let chain = Chain::new(id_seen_by_handler).with(RequestId::new());
+
+        let mut request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+        let response = chain.handle_request(&mut request);
+        let id = response.headers().get(HEADER).unwrap();
+        assert!(!id.is_empty());
+        assert_eq!(response.body().as_bytes().unwrap(), id.as_bytes());
+
+        let raw = b"GET / HTTP/1.1\r\nX-Request-Id: upstream-7\r\n\r\n";
+        let mut request = Request::try_from(&raw[..]).unwrap();
+        let response = chain.handle_request(&mut request);
+        assert_eq!(response.headers().get(HEADER), Some("upstream-7"));
+    }
+}
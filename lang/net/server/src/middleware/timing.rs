@@ -0,0 +1,47 @@
+use super::{Middleware, Next};
+use crate::http::{Request, Response};
+use std::time::Instant;
+
+// Reports how long the rest of the chain took to produce the response,
+// in a Server-Timing header browsers show in their developer tools.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: &mut Request, next: Next) -> Response {
+        let start = Instant::now();
+        let mut response = next.run(request);
+        let elapsed = start.elapsed();
+
+        response.headers_mut().append(
+            "Server-Timing",
+            format!("app;dur={:.3}", elapsed.as_secs_f64() * 1000.0),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::server::Handler;
+    use std::convert::TryFrom;
+    use std::thread;
+    use std::time::Duration;
+
+    fn slow(_: &mut Request) -> Response {
+        thread::sleep(Duration::from_millis(5));
+        Response::builder().body("done")
+    }
+
+    #[test]
+    fn reports_the_time_taken() {
+        let chain = Chain::new(slow).with(Timing);
+
+        let mut request = Request::try_from(&b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+        let response = chain.handle_request(&mut request);
+        let timing = response.headers().get("Server-Timing").unwrap();
+        let dur: f64 = timing.strip_prefix("app;dur=").unwrap().parse().unwrap();
+        assert!(dur >= 5.0, "{}", timing);
+    }
+}
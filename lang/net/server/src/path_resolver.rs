@@ -0,0 +1,287 @@
+use super::http::percent::percent_decode;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// What to do with symbolic links on the way to a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Refuse any path that goes through a symlink.
+    Deny,
+    /// Follow symlinks as long as they end up inside the root.
+    WithinRoot,
+    /// Follow symlinks wherever they point.
+    Follow,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The path tries to leave the root, or is not a path at all.
+    Invalid,
+    /// The path is well formed but policy does not allow serving it.
+    Forbidden,
+    NotFound,
+}
+
+// Maps URL paths to files under a root directory without ever handing out
+// a file outside it.
+//
+// The URL path is checked segment by segment before it touches the file
+// system: "..", NUL bytes, backslashes and anything that would still decode
+// to one of those are rejected rather than normalized, since no legitimate
+// link contains them. Whatever is left can only name something below the
+// root, except through a symlink, which `SymlinkPolicy` decides about.
+pub struct PathResolver {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+    allow_hidden: bool,
+}
+
+impl PathResolver {
+    /// Serve files under `root`, which is canonicalized once, here.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?,
+            symlinks: SymlinkPolicy::WithinRoot,
+            allow_hidden: false,
+        })
+    }
+
+    pub fn symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Serve dotfiles like `.env` and `.git/config`. `.well-known` is always
+    /// served, as ACME challenges and other standard files live there.
+    pub fn allow_hidden(mut self, allow_hidden: bool) -> Self {
+        self.allow_hidden = allow_hidden;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The file `url_path` names, as a canonical path. `url_path` is the
+    /// already percent-decoded path of the request.
+    pub fn resolve(&self, url_path: &str) -> Result<PathBuf, ResolveError> {
+        if !url_path.starts_with('/') {
+            return Err(ResolveError::Invalid);
+        }
+
+        let mut path = self.root.clone();
+        for segment in url_path.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue;
+            }
+            if !is_safe_segment(segment) {
+                return Err(ResolveError::Invalid);
+            }
+            if segment.starts_with('.') && !self.allow_hidden && segment != ".well-known" {
+                return Err(ResolveError::Forbidden);
+            }
+            path.push(segment);
+        }
+
+        if self.symlinks == SymlinkPolicy::Deny && self.has_symlink(&path)? {
+            return Err(ResolveError::Forbidden);
+        }
+
+        let path = fs::canonicalize(&path).map_err(|_| ResolveError::NotFound)?;
+        if self.symlinks != SymlinkPolicy::Follow && !path.starts_with(&self.root) {
+            return Err(ResolveError::Forbidden);
+        }
+        Ok(path)
+    }
+
+    // Whether any component of `path` below the root is a symlink.
+    fn has_symlink(&self, path: &Path) -> Result<bool, ResolveError> {
+        let relative = path
+            .strip_prefix(&self.root)
+            .map_err(|_| ResolveError::Invalid)?;
+
+        let mut current = self.root.clone();
+        for component in relative.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => return Ok(true),
+                Ok(_) => {}
+                Err(_) => return Err(ResolveError::NotFound),
+            }
+        }
+        Ok(false)
+    }
+}
+
+// A segment must be a plain file name, and stay one when decoded again, so
+// a double-encoded "%252e%252e" is caught as well as "%2e%2e".
+fn is_safe_segment(segment: &str) -> bool {
+    let plain = |s: &str| {
+        !s.contains(['\0', '\\'])
+            && s != ".."
+            && matches!(
+                Path::new(s).components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            )
+    };
+    plain(segment) && plain(&percent_decode(segment, false))
+}
+
+/// `path` without empty or "." segments, so "//a/./b" becomes "/a/b", or
+/// None if a segment could step outside the directory it is in.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        if !is_safe_segment(segment) {
+            return None;
+        }
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Whether `path` is `prefix` or lies below it. Whole segments are
+/// compared, so "/app" covers "/app/main.js" but not "/application.js".
+pub fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // root/
+    //   index.html
+    //   .env
+    //   inside -> index.html
+    //   outside -> <temp>/path_resolver_..._secret.txt
+    fn fixture(name: &str) -> PathBuf {
+        let base = env::temp_dir().join(format!("path_resolver_{}_{}", name, std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "home").unwrap();
+        fs::write(root.join(".env"), "SECRET=1").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            let _ = symlink(root.join("index.html"), root.join("inside"));
+            let _ = symlink(base.join("secret.txt"), root.join("outside"));
+        }
+        root
+    }
+
+    #[test]
+    fn canonicalizes_a_relative_root() {
+        let root = fixture("relative");
+        let relative = root.join("..").join("root");
+        let resolver = PathResolver::new(&relative).unwrap();
+        assert_eq!(resolver.root(), fs::canonicalize(&root).unwrap());
+        assert_eq!(
+            resolver.resolve("/index.html"),
+            Ok(fs::canonicalize(root.join("index.html")).unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let resolver = PathResolver::new(fixture("traversal")).unwrap();
+        for path in [
+            "/../secret.txt",
+            "/a/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E%2Fsecret.txt",
+            "/..\\secret.txt",
+            "/index.html%00.png",
+            "/index.html\0",
+        ] {
+            assert_eq!(
+                resolver.resolve(path),
+                Err(ResolveError::Invalid),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(resolver.resolve("index.html"), Err(ResolveError::Invalid));
+    }
+
+    #[test]
+    fn keeps_absolute_paths_under_the_root() {
+        let resolver = PathResolver::new(fixture("absolute")).unwrap();
+        assert_eq!(
+            resolver.resolve("//etc/passwd"),
+            Err(ResolveError::NotFound)
+        );
+        assert!(resolver.resolve("//index.html").is_ok());
+        assert!(resolver.resolve("/./index.html").is_ok());
+    }
+
+    #[test]
+    fn denies_hidden_files() {
+        let root = fixture("hidden");
+        let resolver = PathResolver::new(&root).unwrap();
+        assert_eq!(resolver.resolve("/.env"), Err(ResolveError::Forbidden));
+        assert_eq!(
+            resolver.resolve("/.git/config"),
+            Err(ResolveError::Forbidden)
+        );
+        assert_eq!(
+            resolver.resolve("/.well-known/x"),
+            Err(ResolveError::NotFound)
+        );
+
+        let resolver = PathResolver::new(&root).unwrap().allow_hidden(true);
+        assert!(resolver.resolve("/.env").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn applies_symlink_policy() {
+        let root = fixture("symlinks");
+
+        let resolver = PathResolver::new(&root).unwrap();
+        assert!(resolver.resolve("/inside").is_ok());
+        assert_eq!(resolver.resolve("/outside"), Err(ResolveError::Forbidden));
+
+        let resolver = PathResolver::new(&root)
+            .unwrap()
+            .symlinks(SymlinkPolicy::Deny);
+        assert_eq!(resolver.resolve("/inside"), Err(ResolveError::Forbidden));
+        assert!(resolver.resolve("/index.html").is_ok());
+
+        let resolver = PathResolver::new(&root)
+            .unwrap()
+            .symlinks(SymlinkPolicy::Follow);
+        assert!(resolver.resolve("/outside").is_ok());
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("//a/./b/").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a/../b"), None);
+        assert_eq!(normalize_path("/a/%2e%2e/b"), None);
+    }
+
+    #[test]
+    fn matches_prefixes_on_segments() {
+        assert!(has_path_prefix("/app", "/app"));
+        assert!(has_path_prefix("/app/main.js", "/app"));
+        assert!(has_path_prefix("/app/main.js", "/app/"));
+        assert!(has_path_prefix("/anything", "/"));
+        assert!(!has_path_prefix("/application.js", "/app"));
+        assert!(!has_path_prefix("/ap", "/app"));
+    }
+}
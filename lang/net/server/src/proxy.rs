@@ -0,0 +1,519 @@
+use super::connection::{Connection, ReadError};
+use super::http::chunked::ChunkedReader;
+use super::http::request::parse_header;
+use super::http::{Headers, Method, ParseError, Request, Response, StatusCode};
+use super::path_resolver::{has_path_prefix, normalize_path};
+use super::server::Handler;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// Headers that describe one connection rather than the message, and so are
+// never passed from one side of the proxy to the other (RFC 7230 §6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+// Forwards requests under path prefixes to upstream HTTP/1.1 servers, and
+// hands everything else to the wrapped handler:
+//
+//   ProxyHandler::new(WebsiteHandler::new(public_path))
+//       .route("/api/", "127.0.0.1:8998")
+//
+// Routes match whole segments of the normalized path, so "/api" covers
+// "//api/items" but not "/apiary". The path is passed on unchanged. Host is
+// set to the upstream address, with the client's Host, address and scheme
+// in X-Forwarded-Host, -For and -Proto. Upstream bodies are streamed to the
+// client as they arrive, with their Content-Length when they have one and
+// chunked otherwise. Request bodies are not streamed yet: the connection
+// reads them whole, within `Limits::max_body_size`, before the handler runs,
+// and they are sent on in one piece. Each request opens its own upstream
+// connection.
+//
+// An upstream that cannot be reached or answers with garbage gives 502 Bad
+// Gateway, and one that does not answer in time 504 Gateway Timeout.
+pub struct ProxyHandler<H> {
+    handler: H,
+    routes: Vec<(String, String)>,
+    forwarded_proto: &'static str,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+impl<H: Handler> ProxyHandler<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            routes: Vec::new(),
+            forwarded_proto: "http",
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Forward requests for `prefix` and the paths below it to `upstream`, a
+    /// `host:port` address. Routes are tried in the order they were added.
+    pub fn route(mut self, prefix: &str, upstream: &str) -> Self {
+        self.routes.push((prefix.to_string(), upstream.to_string()));
+        self
+    }
+
+    /// Report clients as having connected over HTTPS in X-Forwarded-Proto.
+    pub fn https(mut self, https: bool) -> Self {
+        self.forwarded_proto = if https { "https" } else { "http" };
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set how long to wait on each read from, or write to, an upstream.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn upstream_for(&self, path: &str) -> Option<&str> {
+        let path = normalize_path(path)?;
+        self.routes
+            .iter()
+            .find(|(prefix, _)| has_path_prefix(&path, prefix))
+            .map(|(_, upstream)| upstream.as_str())
+    }
+
+    fn forward(&self, request: &Request, upstream: &str) -> Result<Response, ProxyError> {
+        let mut stream = self.connect(upstream)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream.write_all(&self.request_head(request, upstream))?;
+        stream.write_all(request.body())?;
+        stream.flush()?;
+
+        read_response(stream, *request.method() == Method::HEAD)
+    }
+
+    fn connect(&self, upstream: &str) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in upstream.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, "upstream address did not resolve")
+        }))
+    }
+
+    fn request_head(&self, request: &Request, upstream: &str) -> Vec<u8> {
+        let mut headers = request.headers().clone();
+        remove_hop_by_hop(&mut headers);
+        // The body has already been read, so there is nothing to continue.
+        headers.remove("Expect");
+        headers.remove("Content-Length");
+
+        if let Some(host) = request.headers().get("Host") {
+            headers.insert("X-Forwarded-Host", host.to_string());
+        }
+        if let Some(addr) = request.remote_addr() {
+            let forwarded_for = match request.headers().get("X-Forwarded-For") {
+                Some(earlier) => format!("{}, {}", earlier, addr.ip()),
+                None => addr.ip().to_string(),
+            };
+            headers.insert("X-Forwarded-For", forwarded_for);
+        }
+        headers.insert("X-Forwarded-Proto", self.forwarded_proto);
+        headers.insert("Host", upstream);
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\n",
+            request.method().as_str(),
+            request.target()
+        );
+        for (name, value) in headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !request.body().is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+}
+
+impl<H: Handler> Handler for ProxyHandler<H> {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let upstream = match self.upstream_for(request.path()) {
+            Some(upstream) => upstream,
+            None => return self.handler.handle_request(request),
+        };
+
+        match self.forward(request, upstream) {
+            Ok(response) => response,
+            Err(e) => {
+                let status = e.status_code();
+                warn!(
+                    "Failed to proxy {} {} to {}: {}",
+                    request.method().as_str(),
+                    request.target(),
+                    upstream,
+                    e
+                );
+                Response::new(status, None)
+            }
+        }
+    }
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        self.handler.handle_bad_request(e)
+    }
+
+    fn handle_panic(&self) -> Response {
+        self.handler.handle_panic()
+    }
+}
+
+// Read the upstream's answer, skipping any 1xx interim responses. The body
+// streams straight from the upstream socket: as it is when its length is
+// known, and chunked again when it is not.
+fn read_response(stream: TcpStream, head_only: bool) -> Result<Response, ProxyError> {
+    let mut connection = Connection::new(stream);
+    let (status, headers) = loop {
+        let head = connection.read_head()?;
+        let (status, headers) = parse_response_head(&head)?;
+        if !status.is_informational() {
+            break (status, headers);
+        }
+    };
+
+    let chunked = headers.contains("Transfer-Encoding");
+    let length = match headers.get("Content-Length") {
+        Some(len) if !chunked => Some(len.parse::<u64>().map_err(|_| ProxyError::Invalid)?),
+        _ => None,
+    };
+
+    let mut response = Response::builder().status(status);
+    let mut forwarded = headers.clone();
+    remove_hop_by_hop(&mut forwarded);
+    for (name, value) in forwarded.iter() {
+        response = response.header(name.to_string(), value.to_string());
+    }
+
+    if status == StatusCode::NoContent || status == StatusCode::NotModified {
+        return Ok(response.empty());
+    }
+    // The answer to HEAD describes the body the way a GET would get it.
+    if head_only {
+        return Ok(match length {
+            Some(len) => response.head(len),
+            None => response.chunked(io::empty()),
+        });
+    }
+
+    let (stream, buffered) = connection.into_parts();
+    let reader = BufReader::new(Cursor::new(buffered).chain(stream));
+    Ok(match length {
+        Some(len) => response.stream(reader, len),
+        None if chunked => response.chunked(ChunkedReader::new(reader)),
+        // Neither length nor chunking: the body runs until the upstream
+        // closes the connection.
+        None => response.chunked(reader),
+    })
+}
+
+// HTTP/1.1 200 OK\r\n...HEADERS...
+fn parse_response_head(head: &[u8]) -> Result<(StatusCode, Headers<'static>), ProxyError> {
+    let head = str::from_utf8(head).map_err(|_| ProxyError::Invalid)?;
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or("");
+    let mut words = status_line.splitn(3, ' ');
+    let (protocol, code) = (words.next(), words.next());
+    if !matches!(protocol, Some("HTTP/1.1") | Some("HTTP/1.0")) {
+        return Err(ProxyError::Invalid);
+    }
+    let status = code
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::try_from(code).ok())
+        .ok_or(ProxyError::Invalid)?;
+
+    let mut headers = Headers::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = parse_header(line).map_err(|_| ProxyError::Invalid)?;
+        headers.append(name.to_string(), value.to_string());
+    }
+    Ok((status, headers))
+}
+
+fn remove_hop_by_hop(headers: &mut Headers) {
+    // Connection also names further headers that are only for this hop.
+    let listed: Vec<String> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_string())
+        .collect();
+    for name in listed
+        .iter()
+        .map(String::as_str)
+        .chain(HOP_BY_HOP.iter().copied())
+    {
+        headers.remove(name);
+    }
+}
+
+#[derive(Debug)]
+enum ProxyError {
+    Io(io::Error),
+    TimedOut,
+    /// The upstream closed the connection or sent something that is not a
+    /// valid HTTP/1.1 response.
+    Invalid,
+}
+
+impl ProxyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::TimedOut => StatusCode::GatewayTimeout,
+            _ => StatusCode::BadGateway,
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<ReadError> for ProxyError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::TimedOut => Self::TimedOut,
+            ReadError::Io(e) => e.into(),
+            ReadError::Closed | ReadError::Parse(_) => Self::Invalid,
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::Invalid => write!(f, "invalid response"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Limits;
+    use crate::http::response::Body;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn call(handler: &impl Handler, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        request.set_remote_addr("192.0.2.7:4000".parse().unwrap());
+        let body = raw.split("\r\n\r\n").nth(1).unwrap_or("");
+        request.set_body(body.as_bytes().to_vec());
+        handler.handle_request(&mut request)
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    // Accepts one connection, hands what it received to `check`, and sends
+    // back `reply`.
+    fn upstream(reply: &'static str, check: fn(&str)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Connection::new(stream);
+            let head = connection.read_head().unwrap();
+            let request = Request::try_from(&head[..]).unwrap();
+            let body = connection
+                .read_body(request.headers(), &Limits::default())
+                .unwrap();
+            check(&format!(
+                "{}{}",
+                String::from_utf8_lossy(&head),
+                String::from_utf8_lossy(&body)
+            ));
+            connection.stream().write_all(reply.as_bytes()).unwrap();
+        });
+        addr
+    }
+
+    fn not_proxied(_: &mut Request) -> Response {
+        Response::builder().body("local")
+    }
+
+    #[test]
+    fn forwards_matching_prefixes() {
+        let addr = upstream(
+            concat!(
+                "HTTP/1.1 201 Created\r\nContent-Length: 7\r\n",
+                "Connection: close, X-Upstream-Hop\r\nX-Upstream-Hop: 1\r\n",
+                "X-Upstream: yes\r\n\r\ncreated",
+            ),
+            |received| {
+                assert!(received.starts_with("POST /api/items?draft=1 HTTP/1.1\r\n"));
+                assert!(received.contains("X-Forwarded-Host: example.com\r\n"));
+                assert!(received.contains("X-Forwarded-For: 192.0.2.7\r\n"));
+                assert!(received.contains("X-Forwarded-Proto: http\r\n"));
+                assert!(!received.contains("Keep-Alive"));
+                assert!(received.ends_with("Content-Length: 4\r\nConnection: close\r\n\r\nitem"));
+            },
+        );
+        let handler = ProxyHandler::new(not_proxied).route("/api/", &addr);
+
+        let response = call(
+            &handler,
+            concat!(
+                "POST /api/items?draft=1 HTTP/1.1\r\nHost: example.com\r\n",
+                "Connection: keep-alive\r\nKeep-Alive: timeout=5\r\n",
+                "Content-Length: 4\r\n\r\nitem",
+            ),
+        );
+        assert_eq!(response.status_code(), StatusCode::Created);
+        assert_eq!(response.headers().get("X-Upstream"), Some("yes"));
+        assert_eq!(response.headers().get("X-Upstream-Hop"), None);
+        assert_eq!(response.headers().get("Connection"), None);
+        assert_eq!(body(response), "created");
+
+        let response = call(&handler, "GET /apix HTTP/1.1\r\n\r\n");
+        assert_eq!(body(response), "local");
+    }
+
+    #[test]
+    fn keeps_content_length_for_head() {
+        let addr = upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nContent-Type: text/html\r\n\r\n",
+            |received| assert!(received.starts_with("HEAD /big HTTP/1.1\r\n")),
+        );
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+
+        let mut response = call(&handler, "HEAD /big HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        let mut out = Vec::new();
+        response.send_head(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 1234\r\n"), "{}", out);
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_bodies_of_unknown_length() {
+        let addr = upstream(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+            |_| {},
+        );
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers().get("Transfer-Encoding"), None);
+        assert_eq!(body(response), "3\r\nabc\r\n0\r\n\r\n");
+
+        let addr = upstream("HTTP/1.0 200 OK\r\n\r\nuntil close", |_| {});
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(body(response), "b\r\nuntil close\r\n0\r\n\r\n");
+
+        // The first chunk reaches the client while the upstream is still
+        // holding back the rest.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Connection::new(stream);
+            connection.read_head().unwrap();
+            let stream = connection.stream();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n")
+                .unwrap();
+            released.recv().unwrap();
+            stream.write_all(b"4\r\nlast\r\n0\r\n\r\n").unwrap();
+        });
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+        let mut response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        let mut reader = match response.take_body() {
+            Body::Chunked(reader) => reader,
+            other => panic!("{:?}", other),
+        };
+        let mut first = [0; 5];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"first");
+        release.send(()).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "last");
+    }
+
+    #[test]
+    fn maps_upstream_failures() {
+        // Nothing listens on an address that was just freed.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+
+        // An upstream that accepts but never answers.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        let handler = ProxyHandler::new(not_proxied)
+            .route("/", &addr)
+            .timeout(Duration::from_millis(100));
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::GatewayTimeout);
+
+        let addr = upstream("HTTP/1.1 banana\r\n\r\n", |_| {});
+        let handler = ProxyHandler::new(not_proxied).route("/", &addr);
+        let response = call(&handler, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadGateway);
+    }
+
+    #[test]
+    fn matches_routes_on_segments() {
+        // Nothing listens here, so a 502 shows the request was proxied.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let handler = ProxyHandler::new(not_proxied).route("/api", &addr);
+
+        for path in ["/api", "/api/items", "//api/items", "/./api/items"].iter() {
+            let response = call(&handler, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert_eq!(response.status_code(), StatusCode::BadGateway, "{}", path);
+        }
+        let response = call(&handler, "GET /apiary HTTP/1.1\r\n\r\n");
+        assert_eq!(body(response), "local");
+    }
+}
@@ -0,0 +1,318 @@
+use super::http::{Method, Request, Response, StatusCode};
+use super::server::Handler;
+
+// Dispatches requests by method and path pattern:
+//
+//   Router::new()
+//       .get("/users/:id", show_user)
+//       .route(Method::POST, "/users", create_user)
+//       .get("/static/*path", static_files)
+//       .mount("/admin", admin_router)
+//       .fallback(WebsiteHandler::new(public_path))
+//
+// `:name` matches one path segment and `*name` the rest of the path; both are
+// available to the handler through `Request::param`. Routes and mounts are
+// tried in the order they were added and the first match wins. A path that
+// matches only under other methods is answered with 405 and an Allow header,
+// anything else with the fallback handler or 404.
+pub struct Router {
+    entries: Vec<Entry>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+enum Entry {
+    Route(Route),
+    Mount(String, Router),
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Box<dyn Handler>,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+enum Resolution<'r> {
+    Found(&'r dyn Handler, Vec<(String, String)>),
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Register `handler` for `method` requests whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` does not start with '/' or has a wildcard segment
+    /// that is not the last one.
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Self {
+        self.entries.push(Entry::Route(Route {
+            method,
+            pattern: parse_pattern(pattern),
+            handler: Box::new(handler),
+        }));
+        self
+    }
+
+    /// Register a GET route. It also answers HEAD requests.
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::PUT, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Self {
+        self.route(Method::DELETE, pattern, handler)
+    }
+
+    /// Hand every path under `prefix` to `router`, which matches its own
+    /// patterns against the rest of the path.
+    pub fn mount(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.entries.push(Entry::Mount(prefix, router));
+        self
+    }
+
+    /// The handler for requests no route matches.
+    pub fn fallback(mut self, handler: impl Handler) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    fn resolve(&self, method: &Method, path: &str) -> Resolution<'_> {
+        let mut allowed = Vec::new();
+
+        for entry in &self.entries {
+            match entry {
+                Entry::Route(route) => {
+                    if let Some(params) = match_pattern(&route.pattern, path) {
+                        let method_matches = route.method == *method
+                            || (*method == Method::HEAD && route.method == Method::GET);
+                        if method_matches {
+                            return Resolution::Found(&*route.handler, params);
+                        }
+                        allowed.push(route.method);
+                    }
+                }
+                Entry::Mount(prefix, router) => {
+                    if let Some(rest) = strip_prefix(path, prefix) {
+                        match router.resolve(method, rest) {
+                            Resolution::Found(handler, params) => {
+                                return Resolution::Found(handler, params)
+                            }
+                            Resolution::MethodNotAllowed(methods) => allowed.extend(methods),
+                            Resolution::NotFound => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        if !allowed.is_empty() {
+            Resolution::MethodNotAllowed(allowed)
+        } else if let Some(fallback) = &self.fallback {
+            Resolution::Found(&**fallback, Vec::new())
+        } else {
+            Resolution::NotFound
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let path = request.path().to_string();
+
+        match self.resolve(request.method(), &path) {
+            Resolution::Found(handler, params) => {
+                for (name, value) in params {
+                    request.set_param(name, value);
+                }
+                handler.handle_request(request)
+            }
+            Resolution::MethodNotAllowed(mut methods) => {
+                if methods.contains(&Method::GET) {
+                    methods.push(Method::HEAD);
+                }
+                let mut allow = Vec::new();
+                for method in methods {
+                    if !allow.contains(&method.as_str()) {
+                        allow.push(method.as_str());
+                    }
+                }
+
+                Response::builder()
+                    .status(StatusCode::MethodNotAllowed)
+                    .header("Allow", allow.join(", "))
+                    .empty()
+            }
+            Resolution::NotFound => Response::new(StatusCode::NotFound, None),
+        }
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(pattern.starts_with('/'), "route patterns start with '/'");
+
+    let segments: Vec<Segment> = split_path(pattern)
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        })
+        .collect();
+
+    let wildcard = segments
+        .iter()
+        .position(|segment| matches!(segment, Segment::Wildcard(_)));
+    if let Some(i) = wildcard {
+        assert!(
+            i == segments.len() - 1,
+            "a wildcard must be the last segment"
+        );
+    }
+
+    segments
+}
+
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let parts: Vec<&str> = split_path(path).collect();
+    let mut params = Vec::new();
+
+    for (i, segment) in pattern.iter().enumerate() {
+        match (segment, parts.get(i)) {
+            (Segment::Wildcard(name), _) => {
+                let rest = parts.get(i..).unwrap_or(&[]).join("/");
+                params.push((name.clone(), rest));
+                return Some(params);
+            }
+            (Segment::Literal(literal), Some(part)) if literal == part => {}
+            (Segment::Param(name), Some(part)) => params.push((name.clone(), part.to_string())),
+            _ => return None,
+        }
+    }
+
+    if parts.len() == pattern.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+// Non-empty segments, so "/a//b/" and "/a/b" match the same routes.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+// "/api/users" under "/api" is "/users"; "/apiary" is not under "/api".
+fn strip_prefix<'p>(path: &'p str, prefix: &str) -> Option<&'p str> {
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        Some("/")
+    } else if rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn call(router: &Router, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        router.handle_request(&mut request)
+    }
+
+    fn text(response: &Response) -> &str {
+        std::str::from_utf8(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    fn echo(request: &mut Request) -> Response {
+        let id = request.param("id").unwrap_or("-").to_string();
+        let path = request.param("path").unwrap_or("-").to_string();
+        Response::builder().body(format!("id={} path={}", id, path))
+    }
+
+    #[test]
+    fn matches_params_and_wildcards() {
+        let router = Router::new()
+            .get("/users/:id", echo)
+            .get("/static/*path", echo);
+
+        assert_eq!(
+            text(&call(&router, "GET /users/42 HTTP/1.1\r\n\r\n")),
+            "id=42 path=-"
+        );
+        assert_eq!(
+            text(&call(&router, "GET /static/css/site.css HTTP/1.1\r\n\r\n")),
+            "id=- path=css/site.css"
+        );
+        let response = call(&router, "GET /users/42/posts HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn answers_405_with_allowed_methods() {
+        let router = Router::new()
+            .get("/users/:id", echo)
+            .delete("/users/:id", echo);
+
+        let response = call(&router, "POST /users/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get("Allow"), Some("GET, DELETE, HEAD"));
+
+        let response = call(&router, "HEAD /users/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn mounts_sub_routers_and_falls_back() {
+        let api = Router::new().get("/users/:id", echo).get("/", echo);
+        let router = Router::new()
+            .mount("/api", api)
+            .fallback(|_: &mut Request| Response::builder().body("fallback"));
+
+        assert_eq!(
+            text(&call(&router, "GET /api/users/7 HTTP/1.1\r\n\r\n")),
+            "id=7 path=-"
+        );
+        assert_eq!(
+            text(&call(&router, "GET /api HTTP/1.1\r\n\r\n")),
+            "id=- path=-"
+        );
+        assert_eq!(
+            text(&call(&router, "GET /apiary HTTP/1.1\r\n\r\n")),
+            "fallback"
+        );
+    }
+}
@@ -1,53 +1,790 @@
-use crate::http::{ParseError, Request, Response, StatusCode};
+use crate::connection::{Connection, Limits, ReadError, Transport};
+use crate::http::response::{Socket, Upgrade};
+use crate::http::{Method, ParseError, Request, Response, StatusCode};
+use crate::shutdown::{ShutdownHandle, Tracked, Tracker};
+use crate::thread_pool::{Backlog, ThreadPool};
+use crate::tls::{HttpsRedirect, TlsConfig};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use std::convert::TryFrom;
-use std::io::Read;
-use std::net::TcpListener;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub trait Handler {
-    fn handle_request(&mut self, request: &Request) -> Response;
+// Workers spend most of their time waiting on clients rather than
+// computing, so there are many more of them than CPUs.
+const WORKERS_PER_CPU: usize = 16;
+const DEFAULT_WORKERS: usize = 64;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+// How often a worker waiting on a quiet connection checks whether other
+// connections are waiting for a worker.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-    fn handle_bad_request(&mut self, e: &ParseError) -> Response {
-        println!("Failed to parse request: {}", e);
-        Response::new(StatusCode::BadRequest, None)
+// Handlers are shared by every worker thread, so they must be thread safe
+// and may only take `&self`. Keep mutable state behind a Mutex or atomics.
+// The request is mutable so routers and wrappers can attach data to it,
+// such as path parameters, before passing it on.
+//
+// `handle_bad_request` answers requests that could not be read, and
+// `handle_panic` requests whose `handle_request` panicked. Wrappers forward
+// both, so the outermost handler decides what every error looks like.
+pub trait Handler: Send + Sync + 'static {
+    fn handle_request(&self, request: &mut Request) -> Response;
+
+    fn handle_bad_request(&self, e: &ParseError) -> Response {
+        warn!("Failed to parse request: {}", e);
+        Response::new(e.status_code(), None)
+    }
+
+    fn handle_panic(&self) -> Response {
+        Response::new(StatusCode::InternalServerError, None)
+    }
+}
+
+// Plain functions and closures work as handlers too.
+impl<F> Handler for F
+where
+    F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+{
+    fn handle_request(&self, request: &mut Request) -> Response {
+        self(request)
     }
 }
 
 pub struct Server {
-    addr: String,
+    addrs: Vec<String>,
+    workers: usize,
+    limits: Limits,
+    tls: Option<TlsConfig>,
+    redirect_addr: Option<String>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
 }
 
 impl Server {
     pub fn new(addr: String) -> Self {
-        Self { addr }
+        let workers = thread::available_parallelism()
+            .map(|n| n.get() * WORKERS_PER_CPU)
+            .unwrap_or(DEFAULT_WORKERS);
+
+        Self {
+            addrs: vec![addr],
+            workers,
+            limits: Limits::default(),
+            tls: None,
+            redirect_addr: None,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Also listen on `addr`, serving it the same way as the first address.
+    pub fn bind(mut self, addr: String) -> Self {
+        self.addrs.push(addr);
+        self
     }
 
-    pub fn run(self, mut handler: impl Handler) {
-        println!("Listening on {}", self.addr);
+    /// Serve HTTPS instead of plain HTTP.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Also listen for plain HTTP on `addr`, answering every request with a
+    /// redirect to the same URL over HTTPS. Only used together with `tls`.
+    pub fn redirect_http(mut self, addr: String) -> Self {
+        self.redirect_addr = Some(addr);
+        self
+    }
+
+    /// Set the number of worker threads handling connections. By default
+    /// there are 16 per CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "a server needs at least one worker");
+        self.workers = workers;
+        self
+    }
 
-        let listener = TcpListener::bind(&self.addr).unwrap();
+    /// Set the largest request body, in bytes, the server will accept.
+    /// Larger requests are answered with 413 Payload Too Large.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
 
-        loop {
-            match listener.accept() {
-                Ok((mut stream, _)) => {
-                    let mut buffer = [0; 1024];
-                    match stream.read(&mut buffer) {
-                        Ok(_) => {
-                            println!("Received a request: {}", String::from_utf8_lossy(&buffer));
+    /// Set how long an idle keep-alive connection is held open.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.keep_alive_timeout = timeout;
+        self
+    }
 
-                            let response = match Request::try_from(&buffer[..]) {
-                                Ok(request) => handler.handle_request(&request),
-                                Err(e) => handler.handle_bad_request(&e),
-                            };
+    /// Set how many requests one connection may make before it is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_requests` is zero.
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        assert!(max_requests > 0, "a connection must be allowed one request");
+        self.limits.max_requests = max_requests;
+        self
+    }
+
+    /// Set how long `run` waits, once shut down, for the requests in flight
+    /// to be answered before it closes their connections.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
 
-                            if let Err(e) = response.send(&mut stream) {
-                                println!("Failed to send response: {}", e);
-                            }
-                        }
-                        Err(e) => println!("Failed to read from connection: {}", e),
+    /// A handle that stops `run` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serve connections until shut down through a `ShutdownHandle`.
+    /// Fails if an address cannot be bound.
+    pub fn run(self, handler: impl Handler) -> io::Result<()> {
+        let tls = self.tls.as_ref().map(TlsConfig::server_config);
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+
+        let mut listeners = Vec::new();
+        for addr in &self.addrs {
+            listeners.push((bind(addr)?, Arc::clone(&handler), tls.clone()));
+            info!(
+                "Listening on {}://{} with {} workers",
+                scheme, addr, self.workers
+            );
+        }
+        if let (Some(_), Some(redirect_addr)) = (&tls, &self.redirect_addr) {
+            let https_port = listeners[0].0.local_addr()?.port();
+            let redirect: Arc<dyn Handler> = Arc::new(HttpsRedirect::new(https_port));
+            listeners.push((bind(redirect_addr)?, redirect, None));
+            info!("Redirecting http://{} to HTTPS", redirect_addr);
+        }
+
+        let pool = ThreadPool::new(self.workers);
+        let backlog = pool.backlog();
+        let tracker = Tracker::new();
+
+        // The listeners are non-blocking so one loop can poll all of them and
+        // notice a shutdown request in between.
+        while !self.shutdown.is_shutdown() {
+            let mut accepted = false;
+            for (listener, handler, tls) in &listeners {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        accepted = true;
+                        let tracked = tracker.register(stream.try_clone().ok());
+                        let handler = Arc::clone(handler);
+                        let tls = tls.clone();
+                        let limits = self.limits;
+                        let backlog = backlog.clone();
+                        pool.execute(move || {
+                            handle_connection(stream, tls, &*handler, &limits, tracked, &backlog)
+                        });
                     }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => error!("Failed to establish a connection: {}", e),
+                }
+            }
+            if !accepted {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        }
+
+        info!("Shutting down, {} connections open", tracker.len());
+        drop(listeners);
+        tracker.close_idle();
+        if !tracker.wait(self.shutdown_timeout) {
+            warn!(
+                "Closing {} connections still busy after {:?}",
+                tracker.len(),
+                self.shutdown_timeout
+            );
+            tracker.close_all();
+        }
+        // Joins the workers, which have nothing left to do.
+        drop(pool);
+        Ok(())
+    }
+}
+
+fn bind(addr: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot listen on {}: {}", addr, e)))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+// The TLS handshake happens on the worker thread, as part of the first
+// read, so a slow client cannot hold up the accept loop.
+fn handle_connection(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    handler: &dyn Handler,
+    limits: &Limits,
+    tracked: Tracked,
+    backlog: &Backlog,
+) {
+    // Some platforms hand out accepted sockets non-blocking, like the
+    // listener they came from.
+    let configured = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(limits.keep_alive_timeout)));
+    if let Err(e) = configured {
+        error!("Failed to set read timeout: {}", e);
+        return;
+    }
+    let peer_addr = stream.peer_addr().ok();
+
+    match tls {
+        Some(config) => {
+            let session = match ServerConnection::new(config) {
+                Ok(session) => session,
+                Err(e) => {
+                    error!("Failed to start TLS session: {}", e);
+                    return;
                 }
-                Err(e) => println!("Failed to establish a connection: {}", e),
+            };
+            let stream = StreamOwned::new(session, stream);
+            let mut connection = Connection::new(stream).with_peer_addr(peer_addr);
+            match serve_connection(&mut connection, handler, limits, &tracked, backlog) {
+                Some(upgrade) => spawn_upgraded(connection, upgrade, tracked, close_tls),
+                None => close_tls(connection.stream()),
+            }
+        }
+        None => {
+            let mut connection = Connection::new(stream).with_peer_addr(peer_addr);
+            if let Some(upgrade) =
+                serve_connection(&mut connection, handler, limits, &tracked, backlog)
+            {
+                spawn_upgraded(connection, upgrade, tracked, |_| {});
             }
         }
     }
 }
+
+// Tell the client the response was not cut short.
+fn close_tls(stream: &mut StreamOwned<ServerConnection, TcpStream>) {
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+}
+
+// An upgraded connection stays open for as long as the new protocol keeps
+// it, so it gets a thread of its own instead of holding on to a worker that
+// other clients' requests are waiting for. `finish` runs once it is done.
+fn spawn_upgraded<S: Transport + Send + 'static>(
+    mut connection: Connection<S>,
+    upgrade: Upgrade,
+    tracked: Tracked,
+    finish: fn(&mut S),
+) {
+    let spawned = thread::Builder::new()
+        .name("upgraded".to_string())
+        .spawn(move || {
+            let buffered = connection.take_buffered();
+            upgrade.run(&mut Upgraded {
+                buffered: Cursor::new(buffered),
+                stream: connection.stream(),
+                tracked: &tracked,
+            });
+            finish(connection.stream());
+        });
+    if let Err(e) = spawned {
+        error!("Failed to start a thread for an upgraded connection: {}", e);
+    }
+}
+
+fn serve_connection<S: Transport>(
+    connection: &mut Connection<S>,
+    handler: &dyn Handler,
+    limits: &Limits,
+    tracked: &Tracked,
+    backlog: &Backlog,
+) -> Option<Upgrade> {
+    let mut served = 0;
+
+    while tracked.idle() {
+        if !wait_for_request(connection, limits, backlog) {
+            return None;
+        }
+        served += 1;
+        match serve_request(
+            connection,
+            handler,
+            limits,
+            tracked,
+            served < limits.max_requests,
+        ) {
+            Next::Request => {}
+            Next::Close => return None,
+            Next::Upgrade(upgrade) => return Some(upgrade),
+        }
+    }
+    None
+}
+
+// What a connection does once it has answered a request.
+enum Next {
+    Request,
+    Close,
+    // The response switched protocols, and the connection is handed over.
+    Upgrade(Upgrade),
+}
+
+impl Next {
+    fn open_if(open: bool) -> Self {
+        if open {
+            Self::Request
+        } else {
+            Self::Close
+        }
+    }
+}
+
+// Wait for the next request in short slices rather than parking in a read
+// for the whole keep-alive timeout, so a quiet connection gives its worker
+// up as soon as other connections are queued for one. Returns false when
+// the connection should be closed instead.
+fn wait_for_request<S: Transport>(
+    connection: &mut Connection<S>,
+    limits: &Limits,
+    backlog: &Backlog,
+) -> bool {
+    let deadline = Instant::now() + limits.keep_alive_timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        match connection.wait_readable(IDLE_POLL_INTERVAL.min(deadline - now)) {
+            Ok(true) => return true,
+            Ok(false) if backlog.is_empty() => {}
+            Ok(false) => {
+                debug!("Closing an idle connection to make room for a waiting one");
+                return false;
+            }
+            Err(e) => {
+                debug!("Failed waiting for a request: {}", e);
+                return false;
+            }
+        }
+    }
+}
+
+// Read one request off the connection, answer it, and report what becomes
+// of the connection afterwards. `reusable` is false when the connection has
+// used up its request allowance.
+fn serve_request<S: Transport>(
+    connection: &mut Connection<S>,
+    handler: &dyn Handler,
+    limits: &Limits,
+    tracked: &Tracked,
+    reusable: bool,
+) -> Next {
+    let head = match connection.read_head() {
+        Ok(head) => head,
+        Err(e) => {
+            if let Some(response) = read_error_response(e, handler) {
+                send_response(connection, response, false, false);
+            }
+            return Next::Close;
+        }
+    };
+    tracked.busy();
+    debug!("Received a request: {}", String::from_utf8_lossy(&head));
+
+    let mut request = match Request::try_from(&head[..]) {
+        Ok(request) => request,
+        Err(e) => {
+            send_response(connection, handler.handle_bad_request(&e), false, false);
+            return Next::Close;
+        }
+    };
+    if let Some(addr) = connection.peer_addr() {
+        request.set_remote_addr(addr);
+    }
+    let keep_alive = reusable && !request.headers().contains_token("Connection", "close");
+    let head_only = *request.method() == Method::HEAD;
+
+    match connection.read_body(request.headers(), limits) {
+        Ok(body) => request.set_body(body),
+        Err(e) => {
+            if let Some(response) = read_error_response(e, handler) {
+                send_response(connection, response, false, head_only);
+            }
+            return Next::Close;
+        }
+    }
+
+    // A panic takes down this request only: the worker thread and the
+    // connection survive it, though the connection is closed to be safe.
+    let response = panic::catch_unwind(AssertUnwindSafe(|| handler.handle_request(&mut request)));
+    // A server shutting down closes connections after the response.
+    let keep_alive = keep_alive && !tracked.is_closing();
+    match response {
+        Ok(mut response) => match response.take_upgrade() {
+            // The connection now belongs to the new protocol, and closes
+            // when it is done.
+            Some(upgrade) if response.status_code() == StatusCode::SwitchingProtocols => {
+                if send_response(connection, response, true, false) {
+                    Next::Upgrade(upgrade)
+                } else {
+                    Next::Close
+                }
+            }
+            _ => Next::open_if(send_response(connection, response, keep_alive, head_only)),
+        },
+        Err(_) => {
+            error!(
+                "Handler panicked serving {} {}",
+                request.method().as_str(),
+                request.target()
+            );
+            send_response(connection, handler.handle_panic(), false, head_only);
+            Next::Close
+        }
+    }
+}
+
+// A connection handed over to another protocol: first the bytes the client
+// sent after its upgrade request, then the stream itself.
+struct Upgraded<'a, S> {
+    buffered: Cursor<Vec<u8>>,
+    stream: &'a mut S,
+    tracked: &'a Tracked,
+}
+
+impl<S: Transport> Read for Upgraded<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Transport> Write for Upgraded<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Transport> Socket for Upgraded<'_, S> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn is_closing(&self) -> bool {
+        self.tracked.is_closing()
+    }
+}
+
+// Returns whether the connection is still open for another request.
+fn send_response<S: Transport>(
+    connection: &mut Connection<S>,
+    mut response: Response,
+    keep_alive: bool,
+    head_only: bool,
+) -> bool {
+    let keep_alive = keep_alive && !response.headers().contains_token("Connection", "close");
+    if !keep_alive {
+        response.headers_mut().insert("Connection", "close");
+    }
+
+    let sent = if head_only {
+        response.send_head(connection.stream())
+    } else {
+        response.send(connection.stream())
+    };
+
+    match sent {
+        Ok(()) => keep_alive,
+        Err(e) => {
+            warn!("Failed to send response: {}", e);
+            false
+        }
+    }
+}
+
+// The response to send when a request could not be read, if the
+// connection is still usable enough to send one.
+fn read_error_response(e: ReadError, handler: &dyn Handler) -> Option<Response> {
+    match e {
+        ReadError::Closed | ReadError::TimedOut => None,
+        ReadError::Io(e) => {
+            warn!("Failed to read from connection: {}", e);
+            None
+        }
+        ReadError::Parse(e) => Some(handler.handle_bad_request(&e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+
+    impl Transport for Cursor<Vec<u8>> {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn wait_readable(&mut self, _: Duration) -> io::Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn answers_panics_with_500() {
+        let handler = |request: &mut Request| {
+            if request.path() == "/boom" {
+                panic!("handler bug");
+            }
+            Response::builder().body("fine")
+        };
+        let raw = b"GET /boom HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut connection = Connection::new(Cursor::new(raw.to_vec()));
+        let tracked = Tracker::new().register(None);
+        serve_connection(
+            &mut connection,
+            &handler,
+            &Limits::default(),
+            &tracked,
+            &Backlog::default(),
+        );
+
+        let written = &connection.stream().get_ref()[raw.len()..];
+        let written = String::from_utf8_lossy(written);
+        assert!(written.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(written.contains("Connection: close\r\n"));
+        assert!(!written.contains("fine"));
+    }
+
+    #[test]
+    fn passes_conformance_suite() {
+        conformance::check(|addr| {
+            let server = Server::new(addr)
+                .workers(2)
+                .max_body_size(conformance::MAX_BODY_SIZE);
+            let shutdown = server.shutdown_handle();
+            let running = thread::spawn(move || server.run(conformance::respond));
+            (shutdown, running)
+        });
+    }
+
+    #[test]
+    fn starts_and_stops_repeatedly() {
+        // The same port each time, so the listener has to be released too.
+        let addr = conformance::free_addr();
+        for _ in 0..3 {
+            let server = Server::new(addr.clone()).workers(2);
+            let shutdown = server.shutdown_handle();
+            let running = thread::spawn(move || server.run(conformance::respond));
+            thread::sleep(Duration::from_millis(50));
+
+            // An idle keep-alive connection must not hold up the shutdown.
+            let mut idle = TcpStream::connect(&addr).unwrap();
+            assert!(conformance::get(&addr, "/a").ends_with("path /a"));
+            let slow = {
+                let addr = addr.clone();
+                thread::spawn(move || conformance::get(&addr, "/slow"))
+            };
+            thread::sleep(Duration::from_millis(50));
+
+            shutdown.shutdown();
+            let response = slow.join().unwrap();
+            assert!(response.contains("Connection: close\r\n"));
+            assert!(response.ends_with("done"));
+            running.join().unwrap().unwrap();
+            assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn idle_connections_give_way() {
+        let addr = conformance::free_addr();
+        let server = Server::new(addr.clone())
+            .workers(2)
+            .keep_alive_timeout(Duration::from_secs(30));
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(conformance::respond));
+        thread::sleep(Duration::from_millis(50));
+
+        // Twice as many quiet connections as workers: some have made a
+        // request and kept the connection, some never sent anything.
+        let mut idle = Vec::new();
+        for i in 0..2 {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            write!(stream, "GET /{} HTTP/1.1\r\n\r\n", i).unwrap();
+            let expected = format!("path /{}", i);
+            let mut response = Vec::new();
+            while !response.ends_with(expected.as_bytes()) {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                response.extend_from_slice(&buf[..n]);
+            }
+            idle.push(stream);
+        }
+        for _ in 0..2 {
+            idle.push(TcpStream::connect(&addr).unwrap());
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /late HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("path /late"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    // Echoes what it is sent until the server shuts down.
+    fn echo(socket: &mut dyn Socket) {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut buf = [0; 64];
+        while !socket.is_closing() {
+            match socket.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => socket.write_all(&buf[..n]).unwrap(),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(_) => return,
+            }
+        }
+    }
+
+    #[test]
+    fn upgraded_connections_leave_the_workers_free() {
+        let addr = conformance::free_addr();
+        let server = Server::new(addr.clone()).workers(1);
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || {
+            server.run(|request: &mut Request| match request.path() {
+                "/echo" => Response::builder().upgrade(echo),
+                _ => conformance::respond(request),
+            })
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let mut upgraded = Vec::new();
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            stream.write_all(b"GET /echo HTTP/1.1\r\n\r\n").unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0; 1];
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+            upgraded.push(stream);
+        }
+        for stream in &mut upgraded {
+            stream.write_all(b"ping").unwrap();
+            let mut echoed = [0; 4];
+            stream.read_exact(&mut echoed).unwrap();
+            assert_eq!(&echoed, b"ping");
+        }
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        stream
+            .write_all(b"GET /plain HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("path /plain"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+        for mut stream in upgraded {
+            assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn reports_bind_failures() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(taken.local_addr().unwrap().to_string());
+        let e = server.run(|_: &mut Request| Response::new(StatusCode::Ok, None));
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn serves_https_with_self_signed_cert() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = env::temp_dir().join(format!("server_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        let tls = TlsConfig::from_pem_files(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let handler = |request: &mut Request| {
+                Response::builder().body(format!("secure {}", request.path()))
+            };
+            handle_connection(
+                stream,
+                Some(tls.server_config()),
+                &handler,
+                &Limits::default(),
+                Tracker::new().register(None),
+                &Backlog::default(),
+            );
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name: ServerName = "localhost".try_into().unwrap();
+        let session = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut stream = StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+
+        stream
+            .write_all(b"GET /page HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nsecure /page"));
+    }
+}
@@ -0,0 +1,208 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Asks a running server to stop. Clones all refer to the same server, so
+// one can be handed to another thread, a test, or a signal handler.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop accepting connections. `Server::run` returns once the requests
+    /// in flight have been answered, or the shutdown timeout has passed.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Shut down on SIGINT or SIGTERM. A second signal while shutting down
+    /// exits the process at once, for when draining takes too long.
+    pub fn on_signals(&self) -> io::Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(
+                signal,
+                1,
+                Arc::clone(&self.requested),
+            )?;
+            signal_hook::flag::register(signal, Arc::clone(&self.requested))?;
+        }
+        Ok(())
+    }
+}
+
+// The connections a server has open, so that shutting down can close the
+// idle ones straight away and wait for the busy ones to finish.
+#[derive(Default)]
+pub struct Tracker {
+    state: Mutex<TrackerState>,
+    drained: Condvar,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    next_id: u64,
+    closing: bool,
+    open: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    stream: Option<TcpStream>,
+    idle: bool,
+}
+
+impl Tracker {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Start tracking a connection. `stream` is a handle to its socket, used
+    /// to close it; None tracks a connection that cannot be closed this way.
+    pub fn register(self: &Arc<Self>, stream: Option<TcpStream>) -> Tracked {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(
+            id,
+            Entry {
+                stream,
+                idle: false,
+            },
+        );
+
+        Tracked {
+            tracker: Arc::clone(self),
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close every connection waiting for a request, and have the others
+    /// close after their current response.
+    pub fn close_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closing = true;
+        for entry in state.open.values().filter(|entry| entry.idle) {
+            if let Some(stream) = &entry.stream {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// Wait until every connection has closed, for at most `timeout`.
+    /// Returns whether they all did.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.open.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.drained.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Cut off every connection, busy or not.
+    pub fn close_all(&self) {
+        let state = self.state.lock().unwrap();
+        for entry in state.open.values() {
+            if let Some(stream) = &entry.stream {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+// One tracked connection. It stops being tracked when this is dropped.
+pub struct Tracked {
+    tracker: Arc<Tracker>,
+    id: u64,
+}
+
+impl Tracked {
+    /// Mark the connection as waiting for its next request. Returns false
+    /// when the server is shutting down, and the connection should close.
+    pub fn idle(&self) -> bool {
+        self.set_idle(true)
+    }
+
+    /// Mark the connection as serving a request.
+    pub fn busy(&self) {
+        self.set_idle(false);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.tracker.state.lock().unwrap().closing
+    }
+
+    fn set_idle(&self, idle: bool) -> bool {
+        let mut state = self.tracker.state.lock().unwrap();
+        if state.closing && idle {
+            return false;
+        }
+        if let Some(entry) = state.open.get_mut(&self.id) {
+            entry.idle = idle;
+        }
+        true
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut state = self.tracker.state.lock().unwrap();
+        state.open.remove(&self.id);
+        if state.open.is_empty() {
+            self.tracker.drained.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn waits_for_busy_connections() {
+        let tracker = Tracker::new();
+        let idle = tracker.register(None);
+        let busy = tracker.register(None);
+        assert!(idle.idle());
+        busy.busy();
+
+        tracker.close_idle();
+        assert!(!idle.idle());
+        assert!(busy.is_closing());
+        drop(idle);
+
+        assert!(!tracker.wait(Duration::from_millis(10)));
+        let finish = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(busy);
+        });
+        assert!(tracker.wait(Duration::from_secs(5)));
+        assert!(tracker.is_empty());
+        finish.join().unwrap();
+    }
+}
@@ -0,0 +1,661 @@
+use super::escape;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+// Includes and layouts nested deeper than this are taken to be a cycle.
+const MAX_DEPTH: usize = 16;
+
+// A parsed template. The syntax is a small subset of Jinja's:
+//
+//   {% extends "layout.html.tmpl" %}
+//   {% block content %}
+//     <h1>{{ data.report.title }}</h1>
+//     {% if query.verbose == "1" %}{% include "details.tmpl" %}{% endif %}
+//     <ul>
+//     {% for row in data.report.rows %}
+//       <li class="{% if loop.first %}first{% endif %}">{{ row.name }}</li>
+//     {% else %}
+//       <li>Nothing to report.</li>
+//     {% endfor %}
+//     </ul>
+//   {% endblock %}
+//   {# A comment. #}
+//
+// `{{ expr }}` is HTML-escaped unless written `{{ expr | raw }}`. An
+// expression is a dotted path into the context, a "string", a number,
+// true, false or null, optionally compared with == or != and negated with
+// `not`. Paths that lead nowhere are null, which prints as nothing and is
+// false like 0, "" and empty lists. Loops run over lists, or over objects
+// as `{key, value}` pairs, and set `loop.index`, `loop.first` and
+// `loop.last`.
+//
+// Template names in include and extends are relative to the template
+// they appear in unless they start with '/'. A template that extends
+// another renders as that layout, with its own blocks in place of the
+// layout's.
+pub struct Template {
+    name: String,
+    extends: Option<String>,
+    nodes: Vec<Node>,
+}
+
+enum Node {
+    Text(String),
+    Output {
+        expr: Expr,
+        raw: bool,
+    },
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        items: Expr,
+        body: Vec<Node>,
+        empty: Vec<Node>,
+        line: usize,
+    },
+    Include {
+        name: String,
+        line: usize,
+    },
+    Block {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+enum Expr {
+    Path(Vec<String>),
+    Literal(Value),
+    Not(Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+}
+
+// Block overrides from the templates extending the one being rendered.
+type Blocks<'t> = HashMap<&'t str, &'t [Node]>;
+
+impl Template {
+    /// Parse `source`. `name` is where it was loaded from, for finding
+    /// the templates it includes and for error messages.
+    pub fn parse(name: &str, source: &str) -> Result<Self, TemplateError> {
+        let mut parser = Parser {
+            name,
+            tokens: tokenize(name, source)?.into_iter(),
+            extends: None,
+        };
+        let (nodes, end) = parser.parse_until(&[])?;
+        if let Some((keyword, _, line)) = end {
+            return Err(parser.error(line, format!("unexpected {{% {} %}}", keyword)));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            extends: parser.extends,
+            nodes,
+        })
+    }
+
+    /// Render with the values in `context`, which is normally an object.
+    /// `load` is called with the name of each template included or
+    /// extended.
+    pub fn render(
+        &self,
+        context: &Value,
+        load: &mut dyn FnMut(&str) -> Result<Template, TemplateError>,
+    ) -> Result<String, TemplateError> {
+        let mut renderer = Renderer {
+            context,
+            load,
+            scopes: Vec::new(),
+            depth: 0,
+        };
+        let mut out = String::new();
+        renderer.render_template(self, &Blocks::new(), &mut out)?;
+        Ok(out)
+    }
+
+    // `name` as seen from this template.
+    fn resolve(&self, name: &str) -> String {
+        if name.starts_with('/') {
+            return name.to_string();
+        }
+        let dir = self.name.rfind('/').map_or("", |end| &self.name[..=end]);
+        format!("{}{}", dir, name)
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Output(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<Token<'a>>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let line_at = |rest: &str| source[..source.len() - rest.len()].matches('\n').count() + 1;
+
+    while let Some(start) = find_delimiter(rest) {
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        let line = line_at(&rest[start..]);
+        let close = match &rest[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let inner = &rest[start + 2..];
+        let end = inner.find(close).ok_or_else(|| {
+            TemplateError(format!(
+                "{}:{}: {} is never closed",
+                name,
+                line,
+                &rest[start..start + 2]
+            ))
+        })?;
+        match close {
+            "}}" => tokens.push(Token::Output(inner[..end].trim(), line)),
+            "%}" => tokens.push(Token::Tag(inner[..end].trim(), line)),
+            _ => {}
+        }
+        rest = &inner[end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+fn find_delimiter(s: &str) -> Option<usize> {
+    s.match_indices('{')
+        .map(|(at, _)| at)
+        .find(|&at| matches!(s.as_bytes().get(at + 1), Some(b'{' | b'%' | b'#')))
+}
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: std::vec::IntoIter<Token<'a>>,
+    extends: Option<String>,
+}
+
+// An end tag: its keyword, what follows the keyword, and its line.
+type EndTag<'a> = (&'a str, &'a str, usize);
+
+impl<'a> Parser<'a> {
+    // Parse nodes up to one of the tags in `ends`, which is returned, or
+    // None at the end of the template.
+    fn parse_until(
+        &mut self,
+        ends: &[&str],
+    ) -> Result<(Vec<Node>, Option<EndTag<'a>>), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let (tag, line) = match token {
+                Token::Text(text) => {
+                    nodes.push(Node::Text(text.to_string()));
+                    continue;
+                }
+                Token::Output(output, line) => {
+                    let (expr, raw) = match output.rsplit_once('|') {
+                        Some((expr, filter)) if filter.trim() == "raw" => (expr, true),
+                        Some((_, filter)) if is_identifier(filter.trim()) => {
+                            return Err(self.error(line, format!("unknown filter {}", filter)))
+                        }
+                        _ => (output, false),
+                    };
+                    nodes.push(Node::Output {
+                        expr: self.parse_expr(expr, line)?,
+                        raw,
+                    });
+                    continue;
+                }
+                Token::Tag(tag, line) => (tag, line),
+            };
+
+            let (keyword, rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let rest = rest.trim();
+            if ends.contains(&keyword) {
+                return Ok((nodes, Some((keyword, rest, line))));
+            }
+            nodes.push(match keyword {
+                "if" => self.parse_if(rest, line)?,
+                "for" => self.parse_for(rest, line)?,
+                "include" => Node::Include {
+                    name: self.parse_name(rest, line)?,
+                    line,
+                },
+                "block" => {
+                    if !is_identifier(rest) {
+                        return Err(self.error(line, format!("invalid block name {}", rest)));
+                    }
+                    let body = self.parse_block("block", &["endblock"], line)?.0;
+                    Node::Block {
+                        name: rest.to_string(),
+                        body,
+                    }
+                }
+                "extends" if self.extends.is_none() => {
+                    self.extends = Some(self.parse_name(rest, line)?);
+                    continue;
+                }
+                _ => return Err(self.error(line, format!("unexpected {{% {} %}}", keyword))),
+            });
+        }
+        Ok((nodes, None))
+    }
+
+    // The body of a tag that must be closed by one of `ends`.
+    fn parse_block(
+        &mut self,
+        keyword: &str,
+        ends: &[&str],
+        line: usize,
+    ) -> Result<(Vec<Node>, EndTag<'a>), TemplateError> {
+        match self.parse_until(ends)? {
+            (nodes, Some(end)) => Ok((nodes, end)),
+            (_, None) => Err(self.error(line, format!("{{% {} %}} is never closed", keyword))),
+        }
+    }
+
+    // {% if a %}..{% elif b %}..{% else %}..{% endif %}
+    fn parse_if(&mut self, condition: &str, line: usize) -> Result<Node, TemplateError> {
+        let mut branches = Vec::new();
+        let mut condition = self.parse_expr(condition, line)?;
+        loop {
+            let (body, (keyword, rest, end_line)) =
+                self.parse_block("if", &["elif", "else", "endif"], line)?;
+            branches.push((condition, body));
+            match keyword {
+                "elif" => condition = self.parse_expr(rest, end_line)?,
+                "else" => {
+                    let otherwise = self.parse_block("if", &["endif"], line)?.0;
+                    return Ok(Node::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                _ => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: Vec::new(),
+                    })
+                }
+            }
+        }
+    }
+
+    // {% for x in xs %}..{% else %}..{% endfor %}
+    fn parse_for(&mut self, head: &str, line: usize) -> Result<Node, TemplateError> {
+        let (var, items) = match head.split_once(" in ") {
+            Some((var, items)) if is_identifier(var.trim()) => (var.trim(), items),
+            _ => return Err(self.error(line, "expected {% for name in list %}".to_string())),
+        };
+        let items = self.parse_expr(items, line)?;
+        let (body, (keyword, _, _)) = self.parse_block("for", &["else", "endfor"], line)?;
+        let empty = match keyword {
+            "else" => self.parse_block("for", &["endfor"], line)?.0,
+            _ => Vec::new(),
+        };
+        Ok(Node::For {
+            var: var.to_string(),
+            items,
+            body,
+            empty,
+            line,
+        })
+    }
+
+    fn parse_name(&self, s: &str, line: usize) -> Result<String, TemplateError> {
+        match self.parse_expr(s, line)? {
+            Expr::Literal(Value::String(name)) => Ok(name),
+            _ => Err(self.error(line, format!("expected a quoted template name, got {}", s))),
+        }
+    }
+
+    fn parse_expr(&self, s: &str, line: usize) -> Result<Expr, TemplateError> {
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("not ") {
+            return Ok(Expr::Not(Box::new(self.parse_expr(rest, line)?)));
+        }
+        for op in ["==", "!="] {
+            if let Some(at) = find_outside_quotes(s, op) {
+                let left = Box::new(self.parse_expr(&s[..at], line)?);
+                let right = Box::new(self.parse_expr(&s[at + 2..], line)?);
+                return Ok(if op == "==" {
+                    Expr::Eq(left, right)
+                } else {
+                    Expr::Ne(left, right)
+                });
+            }
+        }
+
+        if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+            return Ok(Expr::Literal(Value::String(s[1..s.len() - 1].to_string())));
+        }
+        match s {
+            "true" => return Ok(Expr::Literal(Value::Bool(true))),
+            "false" => return Ok(Expr::Literal(Value::Bool(false))),
+            "null" => return Ok(Expr::Literal(Value::Null)),
+            _ => {}
+        }
+        if let Ok(number) = s.parse::<serde_json::Number>() {
+            return Ok(Expr::Literal(Value::Number(number)));
+        }
+        let path: Vec<&str> = s.split('.').collect();
+        if path.iter().all(|segment| is_identifier(segment)) {
+            return Ok(Expr::Path(path.into_iter().map(String::from).collect()));
+        }
+        Err(self.error(line, format!("invalid expression {}", s)))
+    }
+
+    fn error(&self, line: usize, message: String) -> TemplateError {
+        TemplateError(format!("{}:{}: {}", self.name, line, message))
+    }
+}
+
+// Letters, digits, '_' and '-', so header names like user-agent work.
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn find_outside_quotes(s: &str, pattern: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (at, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && s[at..].starts_with(pattern) {
+            return Some(at);
+        }
+    }
+    None
+}
+
+struct Renderer<'a> {
+    context: &'a Value,
+    load: &'a mut dyn FnMut(&str) -> Result<Template, TemplateError>,
+    // Loop variables, innermost last.
+    scopes: Vec<(String, Value)>,
+    depth: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn render_template<'t>(
+        &mut self,
+        template: &'t Template,
+        blocks: &Blocks<'t>,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        let layout = match &template.extends {
+            Some(layout) => layout,
+            None => return self.render_nodes(template, &template.nodes, blocks, out),
+        };
+
+        // Blocks from further down the chain of layouts win.
+        let mut blocks = blocks.clone();
+        collect_blocks(&template.nodes, &mut blocks);
+        let layout = self.load_from(template, layout, 0)?;
+        self.render_nested(&layout, &blocks, out)
+    }
+
+    fn render_nodes<'t>(
+        &mut self,
+        template: &Template,
+        nodes: &'t [Node],
+        blocks: &Blocks<'t>,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output { expr, raw } => {
+                    let text = to_text(&self.eval(expr));
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape::html(&text));
+                    }
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let body = branches
+                        .iter()
+                        .find(|(condition, _)| is_truthy(&self.eval(condition)))
+                        .map_or(otherwise, |(_, body)| body);
+                    self.render_nodes(template, body, blocks, out)?;
+                }
+                Node::For {
+                    var,
+                    items,
+                    body,
+                    empty,
+                    line,
+                } => {
+                    let items = match self.eval(items) {
+                        Value::Array(items) => items,
+                        Value::Object(entries) => entries
+                            .into_iter()
+                            .map(|(key, value)| {
+                                let mut entry = Map::new();
+                                entry.insert("key".to_string(), Value::String(key));
+                                entry.insert("value".to_string(), value);
+                                Value::Object(entry)
+                            })
+                            .collect(),
+                        Value::Null => Vec::new(),
+                        other => {
+                            return Err(TemplateError(format!(
+                                "{}:{}: cannot loop over {}",
+                                template.name, line, other
+                            )))
+                        }
+                    };
+                    if items.is_empty() {
+                        self.render_nodes(template, empty, blocks, out)?;
+                    }
+                    let len = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut info = Map::new();
+                        info.insert("index".to_string(), Value::from(i + 1));
+                        info.insert("first".to_string(), Value::Bool(i == 0));
+                        info.insert("last".to_string(), Value::Bool(i + 1 == len));
+                        self.scopes.push(("loop".to_string(), Value::Object(info)));
+                        self.scopes.push((var.clone(), item));
+                        let rendered = self.render_nodes(template, body, blocks, out);
+                        self.scopes.truncate(self.scopes.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include { name, line } => {
+                    let included = self.load_from(template, name, *line)?;
+                    self.render_nested(&included, &Blocks::new(), out)?;
+                }
+                Node::Block { name, body } => {
+                    let body = blocks.get(name.as_str()).copied().unwrap_or(body);
+                    self.render_nodes(template, body, blocks, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_from(
+        &mut self,
+        template: &Template,
+        name: &str,
+        line: usize,
+    ) -> Result<Template, TemplateError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(TemplateError(format!(
+                "{}:{}: templates nested too deeply at {}",
+                template.name, line, name
+            )));
+        }
+        (self.load)(&template.resolve(name))
+    }
+
+    fn render_nested<'t>(
+        &mut self,
+        template: &'t Template,
+        blocks: &Blocks<'t>,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        self.depth += 1;
+        let rendered = self.render_template(template, blocks, out);
+        self.depth -= 1;
+        rendered
+    }
+
+    fn eval(&self, expr: &Expr) -> Value {
+        match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Path(path) => self.lookup(path).cloned().unwrap_or(Value::Null),
+            Expr::Not(expr) => Value::Bool(!is_truthy(&self.eval(expr))),
+            Expr::Eq(left, right) => Value::Bool(loose_eq(&self.eval(left), &self.eval(right))),
+            Expr::Ne(left, right) => Value::Bool(!loose_eq(&self.eval(left), &self.eval(right))),
+        }
+    }
+
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match self.scopes.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.context.get(first)?,
+        };
+        for segment in rest {
+            value = match value {
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                _ => value.get(segment)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+fn collect_blocks<'t>(nodes: &'t [Node], blocks: &mut Blocks<'t>) {
+    for node in nodes {
+        if let Node::Block { name, body } = node {
+            blocks.entry(name.as_str()).or_insert(body);
+            collect_blocks(body, blocks);
+        }
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(entries) => !entries.is_empty(),
+    }
+}
+
+// Query parameters and headers are always strings, so `query.page == 2`
+// compares by text.
+fn loose_eq(a: &Value, b: &Value) -> bool {
+    a == b || ((a.is_string() || b.is_string()) && to_text(a) == to_text(b))
+}
+
+#[derive(Debug)]
+pub struct TemplateError(pub String);
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: Value) -> Result<String, TemplateError> {
+        let partials: HashMap<&str, &str> = [
+            ("/layout.tmpl", "<title>{% block title %}Reports{% endblock %}</title>{% block body %}{% endblock %}"),
+            ("/reports/row.tmpl", "[{{ row.name }}]"),
+            ("/reports/loop.tmpl", "{% include \"loop.tmpl\" %}"),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let mut load = |name: &str| match partials.get(name) {
+            Some(source) => Template::parse(name, source),
+            None => Err(TemplateError(format!("{} not found", name))),
+        };
+        Template::parse("/reports/page.tmpl", source)?.render(&context, &mut load)
+    }
+
+    #[test]
+    fn escapes_output_unless_raw() {
+        let context = json!({"user": {"name": "<b>Ann</b>"}, "n": 3});
+        assert_eq!(
+            render(
+                "{{ user.name }} {{user.name|raw}} {{ n }} {{ missing.x }}!",
+                context
+            )
+            .unwrap(),
+            "&lt;b&gt;Ann&lt;/b&gt; <b>Ann</b> 3 !"
+        );
+    }
+
+    #[test]
+    fn renders_conditionals_and_loops() {
+        let context = json!({
+            "rows": [{"name": "a"}, {"name": "b"}],
+            "totals": {"x": 1},
+            "query": {"page": "2"},
+        });
+        let source = concat!(
+            "{% for row in rows %}{{ loop.index }}{% include \"row.tmpl\" %}",
+            "{% if loop.last %}.{% else %},{% endif %}{% endfor %}",
+            "{% for t in totals %}{{ t.key }}={{ t.value }}{% endfor %}",
+            "{% for r in nothing %}x{% else %} none{% endfor %}",
+            "{% if query.page == 2 %} p2{% elif query.page %} p?{% endif %}",
+            "{% if not rows.5 %} short{% endif %}{# ignored #}",
+        );
+        assert_eq!(
+            render(source, context).unwrap(),
+            "1[a],2[b].x=1 none p2 short"
+        );
+    }
+
+    #[test]
+    fn fills_layout_blocks() {
+        let source = "{% extends \"/layout.tmpl\" %}{% block body %}<p>{{ x }}</p>{% endblock %}";
+        assert_eq!(
+            render(source, json!({"x": "hi"})).unwrap(),
+            "<title>Reports</title><p>hi</p>"
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = render("line one\n{% if x %}unclosed", json!({})).unwrap_err();
+        assert_eq!(error.0, "/reports/page.tmpl:2: {% if %} is never closed");
+        assert!(render("{{ a b }}", json!({})).is_err());
+        assert!(render("{% endfor %}", json!({})).is_err());
+        assert!(render("{% include \"loop.tmpl\" %}", json!({}))
+            .unwrap_err()
+            .0
+            .contains("nested too deeply"));
+    }
+}
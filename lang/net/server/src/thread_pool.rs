@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// A fixed set of worker threads pulling jobs off a bounded queue.
+// When every worker is busy and the queue is full, `execute` blocks,
+// which keeps the accept loop from piling up unbounded work.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<SyncSender<Job>>,
+    backlog: Backlog,
+}
+
+// How many jobs are waiting for a worker, for jobs that can step aside
+// when others are waiting.
+#[derive(Clone, Debug, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    /// Whether no job is waiting for a worker.
+    pub fn is_empty(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::sync_channel(size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let backlog = Backlog::default();
+
+        let mut workers = Vec::with_capacity(size);
+        for id in 0..size {
+            workers.push(Worker::new(id, Arc::clone(&receiver), backlog.clone()));
+        }
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+            backlog,
+        }
+    }
+
+    /// A handle on the number of jobs waiting for a worker.
+    pub fn backlog(&self) -> Backlog {
+        self.backlog.clone()
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // Counted before sending, so a job blocked on a full queue is
+            // waiting too.
+            self.backlog.0.fetch_add(1, Ordering::SeqCst);
+            if sender.send(Box::new(f)).is_err() {
+                self.backlog.0.fetch_sub(1, Ordering::SeqCst);
+                error!("Failed to dispatch job: all workers have stopped");
+            }
+        }
+    }
+}
+
+// Dropping the sender closes the channel, so each worker finishes the job
+// it is running plus anything still queued, then exits its loop.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    error!("Worker {} panicked", worker.id);
+                }
+            }
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, backlog: Backlog) -> Worker {
+        let thread = thread::spawn(move || loop {
+            let message = receiver.lock().unwrap().recv();
+
+            match message {
+                Ok(job) => {
+                    backlog.0.fetch_sub(1, Ordering::SeqCst);
+                    job()
+                }
+                Err(_) => break,
+            }
+        });
+
+        Worker {
+            id,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn drop_drains_queued_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let pool = ThreadPool::new(2);
+        for _ in 0..8 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                thread::sleep(std::time::Duration::from_millis(10));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+
+        assert_eq!(counter.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn counts_waiting_jobs() {
+        let pool = ThreadPool::new(1);
+        let backlog = pool.backlog();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || released.recv().unwrap());
+        pool.execute(|| {});
+        assert!(!backlog.is_empty());
+
+        release.send(()).unwrap();
+        drop(pool);
+        assert!(backlog.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::http::{Request, Response, StatusCode};
+use crate::server::Handler;
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+// The certificate chain and private key HTTPS connections are served with.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Load a PEM certificate chain and private key, such as the
+    /// `fullchain.pem` and `privkey.pem` files certbot writes.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        let mut certs = BufReader::new(File::open(cert_path)?);
+        let certs = rustls_pemfile::certs(&mut certs).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(invalid_data(
+                "no certificates found in the certificate file",
+            ));
+        }
+
+        let mut key = BufReader::new(File::open(key_path)?);
+        let key = rustls_pemfile::private_key(&mut key)?
+            .ok_or_else(|| invalid_data("no private key found in the key file"))?;
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Answers every plain HTTP request with a redirect to the same URL over
+// HTTPS. 308 rather than 301 so clients repeat POSTs as POSTs.
+pub struct HttpsRedirect {
+    https_port: u16,
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16) -> Self {
+        Self { https_port }
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let host = match request.headers().get("Host") {
+            Some(host) => strip_port(host),
+            None => return Response::new(StatusCode::BadRequest, None),
+        };
+        let authority = if self.https_port == 443 {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, self.https_port)
+        };
+
+        Response::builder()
+            .status(StatusCode::PermanentRedirect)
+            .header(
+                "Location",
+                format!("https://{}{}", authority, request.target()),
+            )
+            .empty()
+    }
+}
+
+// "example.com:8080" → "example.com", "[::1]:8080" → "[::1]"
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn redirect(https_port: u16, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        HttpsRedirect::new(https_port).handle_request(&mut request)
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let response = redirect(443, "GET /a?b=c HTTP/1.1\r\nHost: example.com:80\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::PermanentRedirect);
+        assert_eq!(
+            response.headers().get("Location"),
+            Some("https://example.com/a?b=c")
+        );
+
+        let response = redirect(8443, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert_eq!(
+            response.headers().get("Location"),
+            Some("https://[::1]:8443/")
+        );
+
+        let response = redirect(443, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn rejects_files_without_a_key() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("tls_no_key_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+
+        let e = TlsConfig::from_pem_files(dir.join("cert.pem"), dir.join("cert.pem"))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,45 +1,749 @@
-use super::http::{Method, Request, Response, StatusCode};
+use super::autoindex::Listing;
+use super::http::date::{format_http_date, parse_http_date};
+use super::http::encoding::{self, Encoding};
+use super::http::percent::percent_encode_path;
+use super::http::range::{parse_range, RangeError};
+use super::http::{mime, Headers, Method, Request, Response, StatusCode};
+use super::path_resolver::{
+    has_path_prefix, normalize_path, PathResolver, ResolveError, SymlinkPolicy,
+};
 use super::server::Handler;
-use std::fs;
+use super::template::{Template, TemplateError};
+use serde_json::{Map, Value};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Headers that carry credentials, which templates never see: a page that
+// echoes its headers would otherwise hand them to anyone it is shown to.
+const PRIVATE_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie"];
 
 pub struct WebsiteHandler {
-    public_path: String,
+    resolver: PathResolver,
+    cache_control: Vec<(String, String)>,
+    precompressed: bool,
+    autoindex: bool,
+    templates: bool,
+    template_data: Option<PathBuf>,
+    excluded: Vec<PathBuf>,
 }
 
 impl WebsiteHandler {
+    /// Serve the files under `public_path`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `public_path` does not exist.
     pub fn new(public_path: String) -> Self {
-        Self { public_path }
+        let resolver = PathResolver::new(&public_path)
+            .unwrap_or_else(|e| panic!("cannot serve {}: {}", public_path, e));
+
+        Self {
+            resolver,
+            cache_control: Vec::new(),
+            precompressed: false,
+            autoindex: false,
+            templates: false,
+            template_data: None,
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Send `Cache-Control: value` for every file under the URL path `prefix`.
+    /// When prefixes overlap, the longest one wins.
+    pub fn cache_control(mut self, prefix: &str, value: &str) -> Self {
+        self.cache_control
+            .push((prefix.to_string(), value.to_string()));
+        self
+    }
+
+    /// Serve `style.css.br` or `style.css.gz`, when they exist next to
+    /// `style.css` and the client accepts that encoding, instead of the file.
+    pub fn precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// List the contents of directories that have no index.html, as HTML,
+    /// or as JSON for clients that ask for it in Accept.
+    pub fn autoindex(mut self, autoindex: bool) -> Self {
+        self.autoindex = autoindex;
+        self
+    }
+
+    /// Render `*.tmpl` files as templates, served with the type of the name
+    /// without `.tmpl`, so `report.html.tmpl` is HTML. A directory without
+    /// an index.html is served as its index.html.tmpl.
+    pub fn templates(mut self, templates: bool) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Make the `*.json` files in `dir` available to templates as
+    /// `data.<file stem>`. They are read on every request.
+    pub fn template_data(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.template_data = Some(dir.into());
+        self
+    }
+
+    /// Never serve the files in `dir`, such as a directory of CGI scripts,
+    /// whatever URL they are asked for by. They are answered with 404.
+    pub fn exclude(mut self, dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        self.excluded
+            .push(fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()));
+        self
+    }
+
+    /// Decide whether paths through symlinks are served. By default a
+    /// symlink is followed only when it points inside `public_path`.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.resolver = self.resolver.symlinks(policy);
+        self
+    }
+
+    /// Serve dotfiles, which are answered with 403 Forbidden by default.
+    pub fn allow_hidden(mut self, allow_hidden: bool) -> Self {
+        self.resolver = self.resolver.allow_hidden(allow_hidden);
+        self
+    }
+
+    fn serve_file(&self, request: &Request, file_path: &str) -> Response {
+        let path = match self.resolver.resolve(file_path) {
+            Ok(path) => path,
+            Err(ResolveError::Invalid) => {
+                warn!("Directory Traversal Attack Attempted: {:?}", file_path);
+                return Response::new(StatusCode::BadRequest, None);
+            }
+            Err(ResolveError::Forbidden) => return Response::new(StatusCode::Forbidden, None),
+            Err(ResolveError::NotFound) => return Response::new(StatusCode::NotFound, None),
+        };
+        if self.excluded.iter().any(|dir| path.starts_with(dir)) {
+            return Response::new(StatusCode::NotFound, None);
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                return self.serve_directory(request, file_path, &path)
+            }
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Response::new(StatusCode::NotFound, None),
+        };
+        if self.templates && file_path.ends_with(".tmpl") {
+            return self.serve_template(request, file_path);
+        }
+        let (path, metadata, encoding) = match self.precompressed_sibling(request, file_path) {
+            Some((path, metadata, encoding)) => (path, metadata, Some(encoding)),
+            None => (path, metadata, None),
+        };
+
+        let validators = Validators::new(&metadata);
+        let mut response = if validators.not_modified(request.headers()) {
+            Response::new(StatusCode::NotModified, None)
+        } else {
+            let content_type = mime::from_path(file_path);
+            match file_response(request, &path, content_type, metadata.len(), &validators) {
+                Ok(response) => response,
+                Err(_) => return Response::new(StatusCode::NotFound, None),
+            }
+        };
+
+        // A 304 repeats the validators and caching policy of the full response.
+        let headers = response.headers_mut();
+        if let Some(modified) = validators.modified {
+            headers.insert("Last-Modified", format_http_date(modified));
+        }
+        headers.insert("ETag", validators.etag);
+        if let Some(encoding) = encoding {
+            headers.insert("Content-Encoding", encoding.as_str());
+        }
+        if self.precompressed {
+            headers.insert("Vary", "Accept-Encoding");
+        }
+        if let Some(cache_control) = self.cache_control_for(request.path()) {
+            headers.insert("Cache-Control", cache_control.to_string());
+        }
+
+        response
+    }
+
+    // A directory is served as its index.html, or as a listing of its
+    // contents. Either way its URL has to end in '/' first, or the relative
+    // links in the page would resolve against the parent directory.
+    fn serve_directory(&self, request: &Request, file_path: &str, dir: &Path) -> Response {
+        // The Location is rebuilt from the normalized path: echoing the
+        // target would send "//evil.example/dir" to another host.
+        if !request.path().ends_with('/') {
+            let path = match normalize_path(request.path()) {
+                Some(path) => percent_encode_path(&path).into_owned(),
+                None => return Response::new(StatusCode::BadRequest, None),
+            };
+            let location = match request.target().split_once('?') {
+                Some((_, query)) => format!("{}/?{}", path, query),
+                None => format!("{}/", path),
+            };
+            return Response::builder()
+                .status(StatusCode::MovedPermanently)
+                .header("Location", location)
+                .empty();
+        }
+
+        let mut indexes = vec![format!("{}index.html", file_path)];
+        if self.templates {
+            indexes.push(format!("{}index.html.tmpl", file_path));
+        }
+        for index in indexes {
+            if let Ok(path) = self.resolver.resolve(&index) {
+                if path.is_file() {
+                    return self.serve_file(request, &index);
+                }
+            }
+        }
+        if !self.autoindex {
+            return Response::new(StatusCode::NotFound, None);
+        }
+
+        let listing = match Listing::read(dir, request.path()) {
+            Ok(listing) => listing,
+            Err(e) => {
+                error!("Failed to list {}: {}", dir.display(), e);
+                return Response::new(StatusCode::InternalServerError, None);
+            }
+        };
+        let (content_type, body) = if mime::prefers_json(request.headers().get("Accept")) {
+            ("application/json", listing.to_json())
+        } else {
+            ("text/html; charset=utf-8", listing.to_html())
+        };
+        Response::builder()
+            .header("Content-Type", content_type)
+            .header("Vary", "Accept")
+            .body(body)
+    }
+
+    // Rendered on every request, so there are no validators to revalidate
+    // with. Templates can include any other file under the public path.
+    fn serve_template(&self, request: &Request, file_path: &str) -> Response {
+        let rendered = self.template_context(request).and_then(|context| {
+            let template = self.load_template(file_path)?;
+            template.render(&context, &mut |name| self.load_template(name))
+        });
+        let body = match rendered {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to render {}: {}", file_path, e);
+                return Response::new(StatusCode::InternalServerError, None);
+            }
+        };
+
+        let content_type = mime::from_path(file_path.trim_end_matches(".tmpl"));
+        let mut response = Response::builder().header("Content-Type", content_type);
+        if let Some(cache_control) = self.cache_control_for(request.path()) {
+            response = response.header("Cache-Control", cache_control.to_string());
+        }
+        response.body(body)
+    }
+
+    fn load_template(&self, file_path: &str) -> Result<Template, TemplateError> {
+        let source = self
+            .resolver
+            .resolve(file_path)
+            .ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .ok_or_else(|| TemplateError(format!("cannot read template {}", file_path)))?;
+        Template::parse(file_path, &source)
     }
 
-    fn read_file(&self, file_path: &str) -> Option<String> {
-        let path = format!("{}/{}", self.public_path, file_path);
+    // What templates can use: `path`, `query.<name>` (a list when the
+    // parameter is repeated), `headers.<lowercase name>` for all but the
+    // private headers, and `data.<file>`.
+    fn template_context(&self, request: &Request) -> Result<Value, TemplateError> {
+        let mut query = Map::new();
+        if let Some(query_string) = request.query_string() {
+            for key in query_string.keys() {
+                let values = query_string.get(key).map(|value| value.all());
+                let value = match values.as_deref() {
+                    Some([single]) => Value::from(*single),
+                    Some(all) => Value::from(all.to_vec()),
+                    None => continue,
+                };
+                query.insert(key.to_string(), value);
+            }
+        }
+
+        let mut headers = Map::new();
+        for (name, value) in request.headers().iter() {
+            if PRIVATE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            let name = name.to_ascii_lowercase();
+            let joined = match headers.get(&name) {
+                Some(Value::String(earlier)) => format!("{}, {}", earlier, value),
+                _ => value.to_string(),
+            };
+            headers.insert(name, Value::String(joined));
+        }
 
-        match fs::canonicalize(path) {
-            Ok(path) => {
-                if path.starts_with(&self.public_path) {
-                    fs::read_to_string(path).ok()
-                } else {
-                    println!("Directory Traversal Attack Attempted: {}", file_path);
-                    None
+        let mut data = Map::new();
+        if let Some(dir) = &self.template_data {
+            let read_error = |e: io::Error| {
+                TemplateError(format!(
+                    "cannot read template data {}: {}",
+                    dir.display(),
+                    e
+                ))
+            };
+            for entry in fs::read_dir(dir).map_err(read_error)? {
+                let path = entry.map_err(read_error)?.path();
+                let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(stem) if path.extension().is_some_and(|ext| ext == "json") => stem,
+                    _ => continue,
+                };
+                let text = fs::read_to_string(&path).map_err(read_error)?;
+                let value = serde_json::from_str(&text).map_err(|e| {
+                    TemplateError(format!("invalid JSON in {}: {}", path.display(), e))
+                })?;
+                data.insert(stem.to_string(), value);
+            }
+        }
+
+        let mut context = Map::new();
+        context.insert("path".to_string(), Value::from(request.path()));
+        context.insert("query".to_string(), Value::Object(query));
+        context.insert("headers".to_string(), Value::Object(headers));
+        context.insert("data".to_string(), Value::Object(data));
+        Ok(Value::Object(context))
+    }
+
+    // The precompressed copy of `file_path` in the encoding the client
+    // prefers among those on disk. Its own metadata gives it its own ETag.
+    fn precompressed_sibling(
+        &self,
+        request: &Request,
+        file_path: &str,
+    ) -> Option<(PathBuf, Metadata, Encoding)> {
+        if !self.precompressed {
+            return None;
+        }
+        let accept = request.headers().get("Accept-Encoding")?;
+
+        let mut siblings = Vec::new();
+        for encoding in Encoding::ALL.iter() {
+            let extension = match encoding.extension() {
+                Some(extension) => extension,
+                None => continue,
+            };
+            let path = match self
+                .resolver
+                .resolve(&format!("{}.{}", file_path, extension))
+            {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            if let Ok(metadata) = fs::metadata(&path) {
+                if metadata.is_file() {
+                    siblings.push((path, metadata, *encoding));
                 }
             }
-            Err(_) => None,
+        }
+
+        let available: Vec<Encoding> = siblings.iter().map(|(_, _, encoding)| *encoding).collect();
+        let chosen = encoding::negotiate(accept, &available)?;
+        siblings
+            .into_iter()
+            .find(|(_, _, encoding)| *encoding == chosen)
+    }
+
+    fn cache_control_for(&self, path: &str) -> Option<&str> {
+        self.cache_control
+            .iter()
+            .filter(|(prefix, _)| has_path_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// The whole file, or the byte ranges the client asked for.
+// Range is only honoured on GET and, when If-Range is present, only while
+// the file is still the version the client already has part of.
+fn file_response(
+    request: &Request,
+    path: &Path,
+    content_type: &'static str,
+    len: u64,
+    validators: &Validators,
+) -> io::Result<Response> {
+    let ranges = match request.headers().get("Range") {
+        Some(range)
+            if *request.method() == Method::GET && validators.if_range(request.headers()) =>
+        {
+            parse_range(range, len)
+        }
+        _ => Err(RangeError::Invalid),
+    };
+
+    let response = match ranges {
+        Err(RangeError::Invalid) => Response::builder()
+            .header("Content-Type", content_type)
+            .header("Accept-Ranges", "bytes")
+            .stream(File::open(path)?, len),
+        Err(RangeError::Unsatisfiable) => Response::builder()
+            .status(StatusCode::RangeNotSatisfiable)
+            .header("Content-Range", format!("bytes */{}", len))
+            .empty(),
+        Ok(ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            Response::builder()
+                .status(StatusCode::PartialContent)
+                .header("Content-Type", content_type)
+                .header("Accept-Ranges", "bytes")
+                .header("Content-Range", format!("bytes {}-{}/{}", first, last, len))
+                .stream(open_range(path, first, last)?, last - first + 1)
+        }
+        Ok(ranges) => multipart_response(path, content_type, len, &ranges)?,
+    };
+
+    Ok(response)
+}
+
+// multipart/byteranges (RFC 7233 Appendix A): each range gets its own part
+// headers, and the parts are streamed straight from the file one after another.
+fn multipart_response(
+    path: &Path,
+    content_type: &'static str,
+    len: u64,
+    ranges: &[(u64, u64)],
+) -> io::Result<Response> {
+    let boundary = format!(
+        "{:x}{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+        len
+    );
+
+    let mut body: Box<dyn Read + Send> = Box::new(io::empty());
+    let mut body_len = 0;
+    for &(first, last) in ranges {
+        let part_head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, first, last, len
+        );
+        body_len += part_head.len() as u64 + (last - first + 1);
+        body = Box::new(
+            body.chain(Cursor::new(part_head))
+                .chain(open_range(path, first, last)?),
+        );
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    body_len += closing.len() as u64;
+    body = Box::new(body.chain(Cursor::new(closing)));
+
+    Ok(Response::builder()
+        .status(StatusCode::PartialContent)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .header("Accept-Ranges", "bytes")
+        .stream(body, body_len))
+}
+
+fn open_range(path: &Path, first: u64, last: u64) -> io::Result<impl Read + Send> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(first))?;
+    Ok(file.take(last - first + 1))
+}
+
+// The ETag is built from the modification time and size, like nginx does,
+// so checking it costs a stat instead of hashing the whole file.
+struct Validators {
+    etag: String,
+    modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn new(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let secs = modified.map(unix_secs).unwrap_or(0);
+
+        Self {
+            etag: format!("\"{:x}-{:x}\"", secs, metadata.len()),
+            modified,
+        }
+    }
+
+    // If-Range holds either an entity tag, which must match strongly, or the
+    // exact Last-Modified date the client saw.
+    fn if_range(&self, headers: &Headers) -> bool {
+        match headers.get("If-Range") {
+            None => true,
+            Some(tag) if tag.starts_with('"') => tag == self.etag,
+            Some(tag) if tag.starts_with("W/") => false,
+            Some(date) => match (parse_http_date(date), self.modified) {
+                (Some(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+                _ => false,
+            },
         }
     }
+
+    // RFC 7232 §6: If-None-Match takes precedence and If-Modified-Since is
+    // only looked at when the client sent no entity tags.
+    fn not_modified(&self, headers: &Headers) -> bool {
+        if headers.contains("If-None-Match") {
+            return headers
+                .get_all("If-None-Match")
+                .flat_map(|value| value.split(','))
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || weak_eq(tag, &self.etag));
+        }
+
+        let since = headers.get("If-Modified-Since").and_then(parse_http_date);
+        match (since, self.modified) {
+            // Last-Modified has one second resolution, so compare at that.
+            (Some(since), Some(modified)) => unix_secs(modified) <= unix_secs(since),
+            _ => false,
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Weak comparison: W/"x" matches "x".
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
 }
 
 impl Handler for WebsiteHandler {
-    fn handle_request(&mut self, request: &Request) -> Response {
+    fn handle_request(&self, request: &mut Request) -> Response {
         match request.method() {
-            Method::GET => match request.path() {
-                "/" => Response::new(StatusCode::Ok, self.read_file("index.html")),
-                "/hello" => Response::new(StatusCode::Ok, self.read_file("hello.html")),
-                path => match self.read_file(path) {
-                    Some(contents) => Response::new(StatusCode::Ok, Some(contents)),
-                    None => Response::new(StatusCode::NotFound, None),
-                },
+            Method::GET | Method::HEAD => match request.path() {
+                "/hello" => self.serve_file(request, "/hello.html"),
+                path => self.serve_file(request, path),
             },
-            _ => Response::new(StatusCode::NotFound, None),
+            _ => Response::builder()
+                .status(StatusCode::MethodNotAllowed)
+                .header("Allow", "GET, HEAD")
+                .empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::env;
+
+    fn public_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("website_handler_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+        fs::canonicalize(dir)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn get(handler: &WebsiteHandler, raw: &str) -> Response {
+        let mut request = Request::try_from(raw.as_bytes()).unwrap();
+        handler.handle_request(&mut request)
+    }
+
+    #[test]
+    fn revalidates_with_etag_and_date() {
+        let handler = WebsiteHandler::new(public_dir("conditional"))
+            .cache_control("/", "no-cache")
+            .cache_control("/app.js", "max-age=60");
+
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(response.headers().get("Cache-Control"), Some("max-age=60"));
+        let etag = response.headers().get("ETag").unwrap().to_string();
+        let modified = response.headers().get("Last-Modified").unwrap().to_string();
+
+        let raw = format!(
+            "GET /app.js HTTP/1.1\r\nIf-None-Match: \"x\", W/{}\r\n\r\n",
+            etag
+        );
+        let response = get(&handler, &raw);
+        assert_eq!(response.status_code(), StatusCode::NotModified);
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers().get("ETag"), Some(etag.as_str()));
+
+        let raw = format!(
+            "GET /app.js HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n",
+            modified
+        );
+        assert_eq!(get(&handler, &raw).status_code(), StatusCode::NotModified);
+
+        let raw =
+            "GET /app.js HTTP/1.1\r\nIf-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n";
+        assert_eq!(get(&handler, raw).status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn matches_cache_control_on_segments() {
+        let handler = WebsiteHandler::new(public_dir("cache_segments"))
+            .cache_control("/", "no-cache")
+            .cache_control("/app", "max-age=60");
+
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers().get("Cache-Control"), Some("no-cache"));
+    }
+
+    #[test]
+    fn refuses_excluded_directories() {
+        let dir = public_dir("excluded");
+        fs::create_dir_all(format!("{}/cgi-bin", dir)).unwrap();
+        fs::write(format!("{}/cgi-bin/hello", dir), "#!/bin/sh").unwrap();
+
+        let handler = WebsiteHandler::new(dir.clone()).exclude(format!("{}/cgi-bin", dir));
+        for path in [
+            "/cgi-bin/hello",
+            "//cgi-bin/hello",
+            "/./cgi-bin/hello",
+            "/cgi-bin/",
+        ]
+        .iter()
+        {
+            let response = get(&handler, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert_eq!(response.status_code(), StatusCode::NotFound, "{}", path);
+        }
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let dir = public_dir("precompressed");
+        fs::write(format!("{}/app.js.gz", dir), "gzipped").unwrap();
+        let handler = WebsiteHandler::new(dir).precompressed(true);
+
+        let raw = "GET /app.js HTTP/1.1\r\nAccept-Encoding: br, gzip\r\n\r\n";
+        let response = get(&handler, raw);
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body().len(), Some(7));
+
+        let response = get(&handler, "GET /app.js HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers().get("Content-Encoding"), None);
+        assert_eq!(response.body().len(), Some(14));
+    }
+
+    #[test]
+    fn refuses_unsafe_paths() {
+        let dir = public_dir("unsafe");
+        fs::write(format!("{}/.env", dir), "SECRET=1").unwrap();
+        let handler = WebsiteHandler::new(dir);
+
+        for target in ["/%2e%2e/etc/passwd", "/..%2f..%2fetc/passwd", "/app.js%00"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert_eq!(get(&handler, &raw).status_code(), StatusCode::BadRequest);
         }
+        let response = get(&handler, "GET /.env HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Forbidden);
+    }
+
+    #[test]
+    fn resolves_directories() {
+        let dir = public_dir("directories");
+        fs::create_dir_all(format!("{}/site", dir)).unwrap();
+        fs::create_dir_all(format!("{}/evil.example/a dir", dir)).unwrap();
+        fs::write(format!("{}/site/index.html", dir), "<p>site</p>").unwrap();
+        fs::create_dir_all(format!("{}/build/a dir", dir)).unwrap();
+        fs::write(format!("{}/build/out<1>.txt", dir), "12345").unwrap();
+        fs::write(format!("{}/build/.secret", dir), "").unwrap();
+
+        let handler = WebsiteHandler::new(dir.clone());
+        let response = get(&handler, "GET /site?v=1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::MovedPermanently);
+        assert_eq!(response.headers().get("Location"), Some("/site/?v=1"));
+        let response = get(&handler, "GET //evil.example/a%20dir HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            response.headers().get("Location"),
+            Some("/evil.example/a%20dir/")
+        );
+        let response = get(&handler, "GET /site/ HTTP/1.1\r\n\r\n");
+        assert_eq!(response.body().as_bytes(), None);
+        assert_eq!(response.body().len(), Some(11));
+        let response = get(&handler, "GET /build/ HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+
+        let handler = WebsiteHandler::new(dir).autoindex(true);
+        let response = get(&handler, "GET /build/ HTTP/1.1\r\n\r\n");
+        let html = String::from_utf8(response.body().as_bytes().unwrap().to_vec()).unwrap();
+        assert!(html.contains("<a href=\"a%20dir/\">a dir/</a>"));
+        assert!(html.contains("<a href=\"out%3C1%3E.txt\">out&lt;1&gt;.txt</a></td><td>5</td>"));
+        assert!(!html.contains(".secret"));
+
+        let raw = "GET /build/ HTTP/1.1\r\nAccept: application/json\r\n\r\n";
+        let response = get(&handler, raw);
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("application/json")
+        );
+        let json = String::from_utf8(response.body().as_bytes().unwrap().to_vec()).unwrap();
+        assert!(json.starts_with("[{\"name\":\"a dir\",\"type\":\"directory\""));
+        assert!(json.contains("{\"name\":\"out<1>.txt\",\"type\":\"file\",\"size\":5,"));
+    }
+
+    #[test]
+    fn renders_templates() {
+        let dir = public_dir("templates");
+        let data = format!("{}/data", dir);
+        fs::create_dir_all(format!("{}/reports", dir)).unwrap();
+        fs::create_dir_all(&data).unwrap();
+        fs::write(
+            format!("{}/layout.html.tmpl", dir),
+            "<h1>{% block title %}{% endblock %}</h1>{% block body %}{% endblock %}",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/reports/index.html.tmpl", dir),
+            concat!(
+                "{% extends \"/layout.html.tmpl\" %}{% block title %}{{ query.name }}{% endblock %}",
+                "{% block body %}{% for s in data.sales.rows %}{{ s }};{% endfor %}",
+                "{{ headers.x-team }}{{ headers.cookie }}{{ headers.authorization }}",
+                "{% endblock %}",
+            ),
+        )
+        .unwrap();
+        fs::write(format!("{}/data/sales.json", dir), "{\"rows\": [1, 2]}").unwrap();
+        fs::write(format!("{}/broken.html.tmpl", dir), "{% if %}").unwrap();
+        let handler = WebsiteHandler::new(dir.clone())
+            .templates(true)
+            .template_data(data);
+
+        let raw = concat!(
+            "GET /reports/?name=%3CQ1%3E HTTP/1.1\r\nX-Team: ops\r\n",
+            "Cookie: session=s3cret\r\nAuthorization: Basic dTpw\r\n\r\n",
+        );
+        let response = get(&handler, raw);
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.headers().get("ETag"), None);
+        let html = String::from_utf8(response.body().as_bytes().unwrap().to_vec()).unwrap();
+        assert_eq!(html, "<h1>&lt;Q1&gt;</h1>1;2;ops");
+
+        let response = get(&handler, "GET /broken.html.tmpl HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::InternalServerError);
+
+        // Without templates enabled they are plain files.
+        let handler = WebsiteHandler::new(dir);
+        let response = get(&handler, "GET /layout.html.tmpl HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        assert_eq!(response.body().len(), Some(70));
     }
 }
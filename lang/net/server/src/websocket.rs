@@ -0,0 +1,745 @@
+use super::http::response::Socket;
+use super::http::{Method, Request, Response, StatusCode};
+use super::server::Handler;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{self, ErrorKind};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// RFC 6455 §1.3: appended to the client's key to prove the server read it.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const READ_CHUNK_SIZE: usize = 4096;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+const DEFAULT_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// Talks to the clients of one WebSocket endpoint. Each connection is served
+// on a thread of its own, outside the server's worker pool so open sockets
+// never starve plain requests, which calls these as things happen on it:
+//
+//   struct Feed;
+//
+//   impl WebSocketHandler for Feed {
+//       fn on_message(&self, socket: &mut WebSocket, message: Message) {
+//           let _ = socket.send(&message);
+//       }
+//
+//       fn on_idle(&self, socket: &mut WebSocket) {
+//           let _ = socket.send_text(&latest_stats());
+//       }
+//   }
+//
+//   Router::new().get("/live", WebSocketEndpoint::new(Feed))
+pub trait WebSocketHandler: Send + Sync + 'static {
+    fn on_open(&self, _socket: &mut WebSocket) {}
+
+    fn on_message(&self, socket: &mut WebSocket, message: Message);
+
+    /// Called whenever nothing has arrived for the endpoint's idle interval,
+    /// which makes it the place to push updates from.
+    fn on_idle(&self, _socket: &mut WebSocket) {}
+
+    /// Called once the connection is over. `code` is None when it ended
+    /// without a close frame.
+    fn on_close(&self, _code: Option<CloseCode>) {}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// RFC 6455 §7.4.1. Applications may use their own codes from 3000 to 4999.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: Self = Self(1000);
+    pub const GOING_AWAY: Self = Self(1001);
+    pub const PROTOCOL_ERROR: Self = Self(1002);
+    pub const UNSUPPORTED_DATA: Self = Self(1003);
+    pub const INVALID_DATA: Self = Self(1007);
+    pub const POLICY_VIOLATION: Self = Self(1008);
+    pub const TOO_BIG: Self = Self(1009);
+    pub const INTERNAL_ERROR: Self = Self(1011);
+}
+
+/// The Sec-WebSocket-Accept value answering a Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+// Serves a WebSocketHandler at whatever path it is routed to. Requests that
+// are not a valid version 13 handshake are refused with 400, or with 426
+// Upgrade Required when they are not asking for WebSocket at all.
+pub struct WebSocketEndpoint<W> {
+    handler: Arc<W>,
+    options: Options,
+}
+
+#[derive(Copy, Clone)]
+struct Options {
+    max_message_size: usize,
+    idle_interval: Duration,
+    ping_interval: Duration,
+}
+
+impl<W: WebSocketHandler> WebSocketEndpoint<W> {
+    pub fn new(handler: W) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            options: Options {
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                idle_interval: DEFAULT_IDLE_INTERVAL,
+                ping_interval: DEFAULT_PING_INTERVAL,
+            },
+        }
+    }
+
+    /// Set the largest message, in bytes, a client may send. Larger ones
+    /// close the connection with 1009.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.options.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set how often `on_idle` is called on a quiet connection.
+    pub fn idle_interval(mut self, interval: Duration) -> Self {
+        self.options.idle_interval = interval;
+        self
+    }
+
+    /// Set how long a connection may stay silent before it is pinged. One
+    /// that stays silent as long again after the ping is closed.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.options.ping_interval = interval;
+        self
+    }
+}
+
+impl<W: WebSocketHandler> Handler for WebSocketEndpoint<W> {
+    fn handle_request(&self, request: &mut Request) -> Response {
+        let key = match check_handshake(request) {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+
+        let handler = Arc::clone(&self.handler);
+        let options = self.options;
+        let target = request.target().to_string();
+        Response::builder()
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", accept_key(&key))
+            .upgrade(move |socket| {
+                let mut socket = WebSocket::new(socket, target, options.max_message_size);
+                serve(&*handler, &mut socket, &options);
+            })
+    }
+}
+
+// RFC 6455 §4.2.1. Returns the client's key.
+fn check_handshake(request: &Request) -> Result<String, Response> {
+    let headers = request.headers();
+    if !headers.contains_token("Upgrade", "websocket") {
+        return Err(Response::builder()
+            .status(StatusCode::UpgradeRequired)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .empty());
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::builder()
+            .status(StatusCode::UpgradeRequired)
+            .header("Sec-WebSocket-Version", "13")
+            .empty());
+    }
+
+    let key = headers.get("Sec-WebSocket-Key").unwrap_or("");
+    let key_is_valid = BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16);
+    if *request.method() != Method::GET
+        || !headers.contains_token("Connection", "Upgrade")
+        || !key_is_valid
+    {
+        return Err(Response::new(StatusCode::BadRequest, None));
+    }
+    Ok(key.to_string())
+}
+
+// Runs a connection from the handshake to the end, keeping it alive with
+// pings and closing it when the server shuts down.
+fn serve(handler: &dyn WebSocketHandler, socket: &mut WebSocket, options: &Options) {
+    if let Err(e) = socket.socket.set_read_timeout(Some(options.idle_interval)) {
+        warn!("Failed to set WebSocket read timeout: {}", e);
+        return;
+    }
+    handler.on_open(socket);
+
+    let mut last_heard = Instant::now();
+    let mut pinged = false;
+    let code = loop {
+        if socket.socket.is_closing() && !socket.closing {
+            let _ = socket.close(CloseCode::GOING_AWAY, "server shutting down");
+        }
+
+        match socket.read_event() {
+            Ok(Event::Message(message)) => {
+                last_heard = Instant::now();
+                pinged = false;
+                handler.on_message(socket, message);
+            }
+            Ok(Event::Heard) => {
+                last_heard = Instant::now();
+                pinged = false;
+            }
+            Ok(Event::Idle) => {
+                let silent = last_heard.elapsed();
+                if socket.closing || (pinged && silent >= options.ping_interval * 2) {
+                    // No answer to our close frame or ping: give up on it.
+                    break None;
+                }
+                if !pinged && silent >= options.ping_interval {
+                    pinged = socket.ping(b"").is_ok();
+                }
+                handler.on_idle(socket);
+            }
+            Ok(Event::Closed(code)) => break code,
+            Err(Failure::Protocol(code, reason)) => {
+                let _ = socket.close(code, reason);
+                break Some(code);
+            }
+            Err(Failure::Io(e)) => {
+                debug!("WebSocket connection failed: {}", e);
+                break None;
+            }
+        }
+    };
+    handler.on_close(code);
+}
+
+// One end of an open WebSocket connection, for sending messages to the
+// client. Messages arriving from it are passed to the handler.
+pub struct WebSocket<'a> {
+    socket: &'a mut dyn Socket,
+    target: String,
+    max_message_size: usize,
+    buf: Vec<u8>,
+    // The opcode and data so far of a message arriving in fragments.
+    fragments: Option<(Opcode, Vec<u8>)>,
+    // Whether a close frame has been sent.
+    closing: bool,
+}
+
+enum Event {
+    Message(Message),
+    // Any other frame, which shows the client is still there.
+    Heard,
+    Idle,
+    Closed(Option<CloseCode>),
+}
+
+#[derive(Debug)]
+enum Failure {
+    Protocol(CloseCode, &'static str),
+    Io(io::Error),
+}
+
+impl<'a> WebSocket<'a> {
+    fn new(socket: &'a mut dyn Socket, target: String, max_message_size: usize) -> Self {
+        Self {
+            socket,
+            target,
+            max_message_size,
+            buf: Vec::new(),
+            fragments: None,
+            closing: false,
+        }
+    }
+
+    /// The request target the connection was opened with, such as
+    /// `/live?room=1`.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_text(text),
+            Message::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(Opcode::Text, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(Opcode::Binary, data)
+    }
+
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_frame(Opcode::Ping, payload)
+    }
+
+    /// Start closing the connection. Messages that arrive until the client
+    /// confirms are dropped.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> io::Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        self.closing = true;
+        let mut payload = code.0.to_be_bytes().to_vec();
+        // Control frame payloads are limited to 125 bytes.
+        payload.extend_from_slice(truncate_utf8(reason, 123).as_bytes());
+        self.write_frame(Opcode::Close, &payload)
+    }
+
+    fn write_frame(&mut self, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+        let frame = Frame {
+            fin: true,
+            opcode,
+            payload: payload.to_vec(),
+        };
+        self.socket.write_all(&frame.encode(None))?;
+        self.socket.flush()
+    }
+
+    fn read_event(&mut self) -> Result<Event, Failure> {
+        loop {
+            match Frame::parse(&self.buf, self.max_message_size, true) {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    if let Some(event) = self.handle_frame(frame)? {
+                        return Ok(event);
+                    }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => return Err(e),
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            match self.socket.read(&mut chunk) {
+                Ok(0) => return Ok(Event::Closed(None)),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(Event::Idle)
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Failure::Io(e)),
+            }
+        }
+    }
+
+    // Ok(None) for a fragment of a message that is not complete yet.
+    fn handle_frame(&mut self, frame: Frame) -> Result<Option<Event>, Failure> {
+        match frame.opcode {
+            Opcode::Ping => {
+                self.write_frame(Opcode::Pong, &frame.payload)
+                    .map_err(Failure::Io)?;
+                Ok(Some(Event::Heard))
+            }
+            Opcode::Pong => Ok(Some(Event::Heard)),
+            Opcode::Close => {
+                let code = parse_close(&frame.payload)?;
+                if !self.closing {
+                    // Echo the code to complete the closing handshake.
+                    let _ = self.close(code.unwrap_or(CloseCode::NORMAL), "");
+                }
+                Ok(Some(Event::Closed(code)))
+            }
+            Opcode::Text | Opcode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(Failure::Protocol(
+                        CloseCode::PROTOCOL_ERROR,
+                        "expected a continuation frame",
+                    ));
+                }
+                if frame.fin {
+                    return self.finish(frame.opcode, frame.payload);
+                }
+                self.fragments = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            Opcode::Continuation => {
+                let (opcode, mut data) = self.fragments.take().ok_or(Failure::Protocol(
+                    CloseCode::PROTOCOL_ERROR,
+                    "unexpected continuation frame",
+                ))?;
+                if data.len() + frame.payload.len() > self.max_message_size {
+                    return Err(Failure::Protocol(CloseCode::TOO_BIG, "message too big"));
+                }
+                data.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    return self.finish(opcode, data);
+                }
+                self.fragments = Some((opcode, data));
+                Ok(None)
+            }
+        }
+    }
+
+    fn finish(&mut self, opcode: Opcode, data: Vec<u8>) -> Result<Option<Event>, Failure> {
+        if self.closing {
+            return Ok(Some(Event::Heard));
+        }
+        let message = match opcode {
+            Opcode::Text => Message::Text(String::from_utf8(data).map_err(|_| {
+                Failure::Protocol(CloseCode::INVALID_DATA, "text message is not UTF-8")
+            })?),
+            _ => Message::Binary(data),
+        };
+        Ok(Some(Event::Message(message)))
+    }
+}
+
+// A close frame's payload is empty, or a code followed by a UTF-8 reason.
+fn parse_close(payload: &[u8]) -> Result<Option<CloseCode>, Failure> {
+    let invalid = Failure::Protocol(CloseCode::PROTOCOL_ERROR, "invalid close frame");
+    match payload {
+        [] => Ok(None),
+        [_] => Err(invalid),
+        [high, low, reason @ ..] => {
+            let code = u16::from_be_bytes([*high, *low]);
+            // Codes below 1000, and ones reserved for use outside of frames.
+            if code < 1000 || matches!(code, 1004..=1006 | 1015) || str::from_utf8(reason).is_err()
+            {
+                return Err(invalid);
+            }
+            Ok(Some(CloseCode(code)))
+        }
+    }
+}
+
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+//  0               1               2               3
+//  FIN RSV(3) opcode(4) | MASK len(7) | extended len (0, 2 or 8 bytes)
+//  | masking key (0 or 4 bytes) | payload
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    // Parse a frame off the front of `buf`, returning it with the number of
+    // bytes it took up, or None if it has not fully arrived yet.
+    fn parse(
+        buf: &[u8],
+        max_payload: usize,
+        masked: bool,
+    ) -> Result<Option<(Frame, usize)>, Failure> {
+        let protocol_error = |reason| Failure::Protocol(CloseCode::PROTOCOL_ERROR, reason);
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set"));
+        }
+        let opcode = Opcode::from_u8(buf[0] & 0x0F).ok_or(protocol_error("unknown opcode"))?;
+        if buf[1] & 0x80 != 0 && !masked {
+            return Err(protocol_error("unexpected masked frame"));
+        }
+        if buf[1] & 0x80 == 0 && masked {
+            return Err(protocol_error("client frames must be masked"));
+        }
+
+        let (len, mut at) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() >= 10 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(protocol_error("invalid control frame"));
+        }
+        if len > max_payload as u64 {
+            return Err(Failure::Protocol(CloseCode::TOO_BIG, "message too big"));
+        }
+        let len = len as usize;
+
+        let mask = if masked {
+            if buf.len() < at + 4 {
+                return Ok(None);
+            }
+            at += 4;
+            Some([buf[at - 4], buf[at - 3], buf[at - 2], buf[at - 1]])
+        } else {
+            None
+        };
+        if buf.len() < at + len {
+            return Ok(None);
+        }
+
+        let mut payload = buf[at..at + len].to_vec();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        Ok(Some((
+            Frame {
+                fin,
+                opcode,
+                payload,
+            },
+            at + len,
+        )))
+    }
+
+    // Servers send frames unmasked; clients pass a mask.
+    fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 14);
+        out.push(if self.fin { 0x80 } else { 0 } | self.opcode as u8);
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        if let Some(mask) = mask {
+            out.extend_from_slice(&mask);
+        }
+        let payload_start = out.len();
+        out.extend_from_slice(&self.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut out[payload_start..], mask);
+        }
+        out
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance;
+    use crate::router::Router;
+    use crate::server::Server;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn computes_accept_key() {
+        // The example from RFC 6455 §1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        for len in [0, 125, 126, 0xFFFF, 0x10000] {
+            let frame = Frame {
+                fin: len != 125,
+                opcode: Opcode::Binary,
+                payload: vec![7; len],
+            };
+            let encoded = frame.encode(Some([1, 2, 3, 4]));
+            let (parsed, used) = Frame::parse(&encoded, 1 << 20, true).unwrap().unwrap();
+            assert_eq!(parsed, frame);
+            assert_eq!(used, encoded.len());
+            assert!(Frame::parse(&encoded[..used - 1], 1 << 20, true)
+                .unwrap()
+                .is_none());
+        }
+
+        let unmasked = Frame {
+            fin: true,
+            opcode: Opcode::Text,
+            payload: b"hi".to_vec(),
+        }
+        .encode(None);
+        assert!(matches!(
+            Frame::parse(&unmasked, 1 << 20, true),
+            Err(Failure::Protocol(CloseCode::PROTOCOL_ERROR, _))
+        ));
+        assert!(matches!(
+            Frame::parse(&[0x82, 0xFE, 0x01, 0x00], 255, true),
+            Err(Failure::Protocol(CloseCode::TOO_BIG, _))
+        ));
+    }
+
+    struct Echo;
+
+    impl WebSocketHandler for Echo {
+        fn on_message(&self, socket: &mut WebSocket, message: Message) {
+            let _ = socket.send(&message);
+        }
+    }
+
+    fn client_frame(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        Frame {
+            fin,
+            opcode,
+            payload: payload.to_vec(),
+        }
+        .encode(Some([0x37, 0xfa, 0x21, 0x3d]))
+    }
+
+    fn read_frame(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Frame {
+        loop {
+            if let Some((frame, used)) = Frame::parse(buf, 1 << 20, false).ok().flatten() {
+                buf.drain(..used);
+                return frame;
+            }
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[test]
+    fn echoes_over_an_upgraded_connection() {
+        let addr = conformance::free_addr();
+        let server = Server::new(addr.clone()).workers(2);
+        let shutdown = server.shutdown_handle();
+        let router = Router::new().get("/echo", WebSocketEndpoint::new(Echo));
+        let running = thread::spawn(move || server.run(router));
+        thread::sleep(Duration::from_millis(50));
+
+        let refused = conformance::get(&addr, "/echo");
+        assert!(refused.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        // The first frame follows the handshake in the same packet.
+        let mut handshake = concat!(
+            "GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n",
+            "Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .as_bytes()
+        .to_vec();
+        handshake.extend(client_frame(false, Opcode::Text, b"hel"));
+        stream.write_all(&handshake).unwrap();
+        stream
+            .write_all(&client_frame(true, Opcode::Ping, b"?"))
+            .unwrap();
+        stream
+            .write_all(&client_frame(true, Opcode::Continuation, b"lo"))
+            .unwrap();
+
+        let mut buf = Vec::new();
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(buf.drain(..end).collect()).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Connection: close"));
+
+        let pong = read_frame(&mut stream, &mut buf);
+        assert_eq!((pong.opcode, pong.payload), (Opcode::Pong, b"?".to_vec()));
+        let echo = read_frame(&mut stream, &mut buf);
+        assert_eq!(
+            (echo.opcode, echo.payload),
+            (Opcode::Text, b"hello".to_vec())
+        );
+
+        stream
+            .write_all(&client_frame(true, Opcode::Close, &1000u16.to_be_bytes()))
+            .unwrap();
+        let close = read_frame(&mut stream, &mut buf);
+        assert_eq!(close.opcode, Opcode::Close);
+        assert_eq!(close.payload, 1000u16.to_be_bytes());
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn closes_on_invalid_text() {
+        let addr = conformance::free_addr();
+        let server = Server::new(addr.clone()).workers(1);
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(WebSocketEndpoint::new(Echo)));
+        thread::sleep(Duration::from_millis(50));
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        let mut request = concat!(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n",
+            "Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .as_bytes()
+        .to_vec();
+        request.extend(client_frame(true, Opcode::Text, &[0xff, 0xfe]));
+        stream.write_all(&request).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let close = read_frame(&mut stream, &mut response[end..].to_vec());
+        assert_eq!(close.opcode, Opcode::Close);
+        assert_eq!(close.payload[..2], 1007u16.to_be_bytes());
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}